@@ -1,4 +1,5 @@
 use crate::{
+    heap::Heap,
     opcode::{CouldNotDecodeOpcode, Opcode},
     value::Value,
 };
@@ -7,7 +8,17 @@ use crate::{
 pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: Vec<Value>,
-    pub lines: Vec<usize>,
+    /// Source line numbers, run-length encoded: one entry for each run of bytecode that
+    /// came from the same line.
+    pub lines: Vec<LineRun>,
+}
+
+/// A run of bytecode compiled from one source line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineRun {
+    /// Offset of the run's first byte.
+    pub start: usize,
+    pub line: usize,
 }
 
 impl Chunk {
@@ -16,59 +27,173 @@ impl Chunk {
     }
 
     pub fn write(&mut self, byte: u8, line: usize) {
+        if self.lines.last().is_none_or(|run| run.line != line) {
+            self.lines.push(LineRun {
+                start: self.code.len(),
+                line,
+            });
+        }
         self.code.push(byte);
-        self.lines.push(line);
     }
 
-    pub fn add_constant(&mut self, value: Value) -> u8 {
+    /// The source line that the byte at `offset` was compiled from.
+    pub fn line_at(&self, offset: usize) -> usize {
+        let run = self.lines.partition_point(|run| run.start <= offset);
+        self.lines[run - 1].line
+    }
+
+    pub fn add_constant(&mut self, value: Value) -> usize {
         self.constants.push(value);
-        (self.constants.len() - 1) as u8
+        self.constants.len() - 1
     }
 
-    #[allow(dead_code)]
-    pub fn disassemble(&self, name: &str) {
+    pub fn disassemble(&self, name: &str, heap: &Heap) {
         println!("== {name} ==");
         let mut offset = 0usize;
         while offset < self.len() {
-            offset = self.disassemble_instruction(offset);
+            offset = self.disassemble_instruction(offset, heap);
         }
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
+    pub fn disassemble_instruction(&self, offset: usize, heap: &Heap) -> usize {
         print!("{offset:04} ");
 
         // Print the line information.
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
+        let line = self.line_at(offset);
+        if offset > 0 && line == self.line_at(offset - 1) {
             print!("   | ")
         } else {
-            print!("{:4} ", self.lines[offset])
+            print!("{line:4} ")
         }
 
-        match Opcode::try_from(self.code[offset]) {
-            Ok(Opcode::Return) => simple_instruction("OP_RETURN", offset),
-            Ok(Opcode::Negate) => simple_instruction("OP_NEGATE", offset),
-            Ok(Opcode::Add) => simple_instruction("OP_ADD", offset),
-            Ok(Opcode::Sub) => simple_instruction("OP_SUBTRACT", offset),
-            Ok(Opcode::Mul) => simple_instruction("OP_MULTIPLY", offset),
-            Ok(Opcode::Div) => simple_instruction("OP_DIVIDE", offset),
-            Ok(Opcode::Constant) => self.constant_instruction("OP_CONSTANT", offset),
+        // OP_WIDE widens the constant operand of the instruction after it.
+        let wide = self.code[offset] == Opcode::Wide as u8;
+        let at = offset + usize::from(wide);
+        match Opcode::try_from(self.code[at]) {
+            Ok(Opcode::Return) => simple_instruction("OP_RETURN", at),
+            Ok(Opcode::Negate) => simple_instruction("OP_NEGATE", at),
+            Ok(Opcode::Add) => simple_instruction("OP_ADD", at),
+            Ok(Opcode::Sub) => simple_instruction("OP_SUBTRACT", at),
+            Ok(Opcode::Mul) => simple_instruction("OP_MULTIPLY", at),
+            Ok(Opcode::Div) => simple_instruction("OP_DIVIDE", at),
+            Ok(Opcode::Nil) => simple_instruction("OP_NIL", at),
+            Ok(Opcode::True) => simple_instruction("OP_TRUE", at),
+            Ok(Opcode::False) => simple_instruction("OP_FALSE", at),
+            Ok(Opcode::Not) => simple_instruction("OP_NOT", at),
+            Ok(Opcode::Equal) => simple_instruction("OP_EQUAL", at),
+            Ok(Opcode::Greater) => simple_instruction("OP_GREATER", at),
+            Ok(Opcode::Less) => simple_instruction("OP_LESS", at),
+            Ok(Opcode::Print) => simple_instruction("OP_PRINT", at),
+            Ok(Opcode::Pop) => simple_instruction("OP_POP", at),
+            Ok(Opcode::DefineGlobal) => {
+                self.constant_instruction("OP_DEFINE_GLOBAL", at, wide, heap)
+            }
+            Ok(Opcode::GetGlobal) => self.constant_instruction("OP_GET_GLOBAL", at, wide, heap),
+            Ok(Opcode::SetGlobal) => self.constant_instruction("OP_SET_GLOBAL", at, wide, heap),
+            Ok(Opcode::GetLocal) => self.byte_instruction("OP_GET_LOCAL", at),
+            Ok(Opcode::SetLocal) => self.byte_instruction("OP_SET_LOCAL", at),
+            Ok(Opcode::Jump) => self.jump_instruction("OP_JUMP", true, at),
+            Ok(Opcode::JumpIfFalse) => self.jump_instruction("OP_JUMP_IF_FALSE", true, at),
+            Ok(Opcode::Loop) => self.jump_instruction("OP_LOOP", false, at),
+            Ok(Opcode::Call) => self.byte_instruction("OP_CALL", at),
+            Ok(Opcode::Closure) => self.closure_instruction(at, wide, heap),
+            Ok(Opcode::GetUpvalue) => self.byte_instruction("OP_GET_UPVALUE", at),
+            Ok(Opcode::SetUpvalue) => self.byte_instruction("OP_SET_UPVALUE", at),
+            Ok(Opcode::CloseUpvalue) => simple_instruction("OP_CLOSE_UPVALUE", at),
+            Ok(Opcode::Class) => self.constant_instruction("OP_CLASS", at, wide, heap),
+            Ok(Opcode::GetProperty) => self.constant_instruction("OP_GET_PROPERTY", at, wide, heap),
+            Ok(Opcode::SetProperty) => self.constant_instruction("OP_SET_PROPERTY", at, wide, heap),
+            Ok(Opcode::Method) => self.constant_instruction("OP_METHOD", at, wide, heap),
+            Ok(Opcode::Inherit) => simple_instruction("OP_INHERIT", at),
+            Ok(Opcode::GetSuper) => self.constant_instruction("OP_GET_SUPER", at, wide, heap),
+            Ok(Opcode::Constant) => self.constant_instruction("OP_CONSTANT", at, wide, heap),
+            Ok(Opcode::ConstantLong) => self.constant_long_instruction(at, heap),
+            Ok(Opcode::ToString) => simple_instruction("OP_TO_STRING", at),
+            Ok(Opcode::Wide) => simple_instruction("OP_WIDE", at),
             Err(CouldNotDecodeOpcode { opcode }) => {
                 println!("Unknown opcode {opcode}");
-                offset + 1
+                at + 1
             }
         }
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
-        let constant: u8 = self.code[offset + 1];
-        print!("{name:<16} {constant:4} ");
-        self.constants[constant as usize].print();
-        println!();
+    /// An instruction with a one-byte operand, such as a stack slot.
+    fn byte_instruction(&self, name: &str, offset: usize) -> usize {
+        let slot = self.code[offset + 1];
+        println!("{name:<16} {slot:4}");
         offset + 2
     }
+
+    /// An instruction with a two-byte jump distance. Prints where the jump lands.
+    fn jump_instruction(&self, name: &str, forwards: bool, offset: usize) -> usize {
+        let jump = u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]) as usize;
+        let next = offset + 3;
+        let target = if forwards { next + jump } else { next - jump };
+        println!("{name:<16} {offset:4} -> {target}");
+        next
+    }
+
+    /// OP_CLOSURE is followed by a (is_local, index) pair for each captured variable.
+    fn closure_instruction(&self, offset: usize, wide: bool, heap: &Heap) -> usize {
+        let (constant, mut offset) = self.constant_operand(offset, wide);
+        let value = self.constants[constant];
+        println!("{:<16} {constant:4} {}", "OP_CLOSURE", value.display(heap));
+
+        let upvalue_count = match value {
+            Value::Obj(obj) => heap.as_function(obj).map_or(0, |f| f.upvalue_count),
+            _ => 0,
+        };
+        for _ in 0..upvalue_count {
+            let is_local = self.code[offset];
+            let index = self.code[offset + 1];
+            let kind = if is_local == 1 { "local" } else { "upvalue" };
+            println!("{offset:04}    |                     {kind} {index}");
+            offset += 2;
+        }
+        offset
+    }
+
+    fn constant_instruction(&self, name: &str, offset: usize, wide: bool, heap: &Heap) -> usize {
+        let (constant, next) = self.constant_operand(offset, wide);
+        let value = self.constants[constant].display(heap);
+        println!("{name:<16} {constant:4} '{value}'");
+        next
+    }
+
+    /// OP_CONSTANT_LONG's operand is a three-byte, big-endian constant index.
+    fn constant_long_instruction(&self, offset: usize, heap: &Heap) -> usize {
+        self.constant_instruction("OP_CONSTANT_LONG", offset, true, heap)
+    }
+
+    /// The constant index after the opcode at `offset`, and the offset of the next
+    /// instruction. Wide operands are three bytes, big-endian.
+    fn constant_operand(&self, offset: usize, wide: bool) -> (usize, usize) {
+        if wide {
+            let [hi, mid, lo] = [1, 2, 3].map(|i| self.code[offset + i]);
+            (u32::from_be_bytes([0, hi, mid, lo]) as usize, offset + 4)
+        } else {
+            (self.code[offset + 1] as usize, offset + 2)
+        }
+    }
 }
 
 fn simple_instruction(name: &str, offset: usize) -> usize {
     println!("{name:<16}");
     offset + 1
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_run_length_encoded() {
+        let mut chunk = Chunk::default();
+        for (byte, line) in [(0, 1), (1, 1), (2, 1), (3, 3), (4, 3), (5, 1)] {
+            chunk.write(byte, line);
+        }
+        assert_eq!(chunk.lines.len(), 3);
+        let lines: Vec<_> = (0..chunk.len()).map(|i| chunk.line_at(i)).collect();
+        assert_eq!(lines, [1, 1, 1, 3, 3, 1]);
+    }
+}
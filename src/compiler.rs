@@ -1,69 +1,544 @@
+use std::{borrow::Cow, collections::HashMap};
+
 use crate::{
     chunk::Chunk,
+    object::{ObjFunction, ObjRef, Object},
     opcode::Opcode,
-    tokenizer::{Scanner, Token, TokenType},
-    vm::CompileErr,
+    tokenizer::{Scanner, Span, Token, TokenType},
+    value::Value,
+    vm::{CompileErr, Vm},
 };
 
-pub(crate) fn compile(source: &str, chunk: &mut Chunk) -> Result<(), CompileErr> {
+/// Compile a whole program into a function that takes no arguments, and allocate it on the heap.
+/// If the program has errors, returns as many of them as can be found in one pass.
+pub(crate) fn compile(source: &str, vm: &mut Vm) -> Result<ObjRef, Vec<CompileErr>> {
     let mut parser = Parser {
-        current_chunk: chunk,
+        vm,
         scanner: Scanner::new(source),
         current: None,
         previous: None,
+        compilers: vec![Compiler::new(FunctionType::Script, None)],
+        classes: Vec::new(),
+        errors: Vec::new(),
+        panic_mode: false,
     };
     // Get the scanner started.
-    parser.advance()?;
-    // Parse an expression.
-    // scanner.expression();
-    // Validate that we are at the end of the source code.
-    parser.consume(TokenType::Eof, "Expected end of expression.")?;
-    end_compiler(&mut parser);
-    Ok(())
+    parser.advance();
+    // Parse declarations until we reach the end of the source code.
+    while !parser.match_token(TokenType::Eof) {
+        parser.declaration();
+    }
+    let (function, _) = parser.end_compiler();
+    if !parser.errors.is_empty() {
+        return Err(parser.errors);
+    }
+    Ok(parser.alloc(Object::Function(function)))
+}
+
+/// The delimiters of a raw string literal.
+const RAW_QUOTES: &str = "\"\"\"";
+
+/// A malformed escape sequence in a string literal.
+struct EscapeError {
+    /// Where the escape sequence starts, as a byte offset into the string's contents.
+    offset: usize,
+    length: usize,
+    msg: &'static str,
+}
+
+/// The text of a string literal, or of one segment of an interpolated string.
+fn string_contents(token: Token<'_>) -> Result<Cow<'_, str>, CompileErr> {
+    let lexeme = token.lexeme;
+    // Triple-quoted strings are raw, the rest may contain escapes.
+    if token.token_type == TokenType::String && lexeme.starts_with(RAW_QUOTES) {
+        return Ok(Cow::Borrowed(
+            &lexeme[RAW_QUOTES.len()..lexeme.len() - RAW_QUOTES.len()],
+        ));
+    }
+    // Trim the opening quote, or the `}` ending the interpolation before this segment, and
+    // the closing quote, or the `${` starting the next interpolation.
+    let contents = match token.token_type {
+        TokenType::Interpolation => lexeme.strip_prefix('"').and_then(|s| s.strip_suffix("${")),
+        TokenType::InterpolationMid => lexeme.strip_prefix('}').and_then(|s| s.strip_suffix("${")),
+        TokenType::InterpolationEnd => lexeme.strip_prefix('}').and_then(|s| s.strip_suffix('"')),
+        _ => lexeme.strip_prefix('"').and_then(|s| s.strip_suffix('"')),
+    };
+    let contents = contents.expect("the scanner only makes string tokens with delimiters");
+    unescape(contents).map_err(|err| CompileErr::Other {
+        span: span_within(token, err.offset + 1, err.length),
+        msg: err.msg,
+    })
+}
+
+/// Decode the escape sequences in a string literal's contents.
+fn unescape(contents: &str) -> Result<Cow<'_, str>, EscapeError> {
+    if !contents.contains('\\') {
+        return Ok(Cow::Borrowed(contents));
+    }
+    let mut decoded = String::with_capacity(contents.len());
+    let mut chars = contents.char_indices();
+    while let Some((offset, c)) = chars.next() {
+        if c != '\\' {
+            decoded.push(c);
+            continue;
+        }
+        let error = |length, msg| EscapeError {
+            offset,
+            length,
+            msg,
+        };
+        let c = match chars.next() {
+            Some((_, 'n')) => '\n',
+            Some((_, 'r')) => '\r',
+            Some((_, 't')) => '\t',
+            Some((_, '\\')) => '\\',
+            Some((_, '"')) => '"',
+            // So that `\${` can be written without starting an interpolation.
+            Some((_, '$')) => '$',
+            Some((_, 'u')) => {
+                // A Unicode scalar value, written like \u{1F600}.
+                let Some((hex, _)) = contents[offset + 2..]
+                    .strip_prefix('{')
+                    .and_then(|rest| rest.split_once('}'))
+                else {
+                    return Err(error(2, "Expect unicode escape like '\\u{1F600}'."));
+                };
+                let length = hex.len() + 4;
+                let is_valid =
+                    (1..=6).contains(&hex.len()) && hex.chars().all(|c| c.is_ascii_hexdigit());
+                let c = u32::from_str_radix(hex, 16)
+                    .ok()
+                    .filter(|_| is_valid)
+                    .and_then(char::from_u32)
+                    .ok_or(error(length, "Invalid unicode escape."))?;
+                // Skip the braces and digits.
+                chars.nth(hex.len() + 1);
+                c
+            }
+            Some((_, other)) => {
+                return Err(error(1 + other.len_utf8(), "Invalid escape sequence."));
+            }
+            // The scanner never ends a string with an escaped closing quote.
+            None => return Err(error(1, "Invalid escape sequence.")),
+        };
+        decoded.push(c);
+    }
+    Ok(Cow::Owned(decoded))
+}
+
+/// Convert a number literal, which the scanner has already validated, to the nearest `f64`.
+fn parse_number(lexeme: &str) -> Result<f64, &'static str> {
+    let digits = lexeme.replace('_', "");
+    let radix = match digits.get(..2) {
+        Some("0x" | "0X") => 16,
+        Some("0b" | "0B") => 2,
+        Some("0o" | "0O") => 8,
+        _ => 10,
+    };
+    let value = if radix == 10 {
+        digits.parse().map_err(|_| "Invalid number literal.")?
+    } else {
+        // Casting rounds to the nearest f64, where summing digit by digit could drift.
+        u128::from_str_radix(&digits[2..], radix).map_err(|_| "Number literal is too large.")?
+            as f64
+    };
+    if value.is_infinite() {
+        return Err("Number literal is too large.");
+    }
+    Ok(value)
+}
+
+/// The span of part of a token's lexeme, given its byte offset and length.
+fn span_within(token: Token, offset: usize, length: usize) -> Span {
+    let before = &token.lexeme[..offset];
+    let column = match before.rfind('\n') {
+        Some(newline) => before[newline + 1..].chars().count() + 1,
+        None => token.column + before.chars().count(),
+    };
+    Span {
+        start: token.start + offset,
+        length,
+        line: token.line + before.matches('\n').count(),
+        column,
+    }
+}
+
+/// Binding power of each kind of expression, from loosest to tightest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! -
+    Call,       // . ()
+    Primary,
+}
+
+impl Precedence {
+    /// The next-tightest precedence level.
+    pub(crate) fn next(self) -> Self {
+        match self {
+            Self::None => Self::Assignment,
+            Self::Assignment => Self::Or,
+            Self::Or => Self::And,
+            Self::And => Self::Equality,
+            Self::Equality => Self::Comparison,
+            Self::Comparison => Self::Term,
+            Self::Term => Self::Factor,
+            Self::Factor => Self::Unary,
+            Self::Unary => Self::Call,
+            Self::Call | Self::Primary => Self::Primary,
+        }
+    }
+}
+
+/// Parses one kind of expression. The flag says whether an `=` following
+/// the expression may be treated as assignment.
+type ParseFn<'src, 'heap> = fn(&mut Parser<'src, 'heap>, bool) -> Result<(), CompileErr>;
+
+/// A row in the Pratt parser table: how to parse a token found at the start
+/// of an expression (prefix), how to parse it after a left operand (infix),
+/// and how tightly it binds as an infix operator.
+struct ParseRule<'src, 'heap> {
+    prefix: Option<ParseFn<'src, 'heap>>,
+    infix: Option<ParseFn<'src, 'heap>>,
+    precedence: Precedence,
+}
+
+impl<'src, 'heap> ParseRule<'src, 'heap> {
+    fn new(
+        prefix: Option<ParseFn<'src, 'heap>>,
+        infix: Option<ParseFn<'src, 'heap>>,
+        precedence: Precedence,
+    ) -> Self {
+        Self {
+            prefix,
+            infix,
+            precedence,
+        }
+    }
+}
+
+fn get_rule<'src, 'heap>(token_type: TokenType) -> ParseRule<'src, 'heap> {
+    match token_type {
+        TokenType::LeftParen => {
+            ParseRule::new(Some(Parser::grouping), Some(Parser::call), Precedence::Call)
+        }
+        TokenType::Dot => ParseRule::new(None, Some(Parser::dot), Precedence::Call),
+        TokenType::Minus => {
+            ParseRule::new(Some(Parser::unary), Some(Parser::binary), Precedence::Term)
+        }
+        TokenType::Plus => ParseRule::new(None, Some(Parser::binary), Precedence::Term),
+        TokenType::Slash => ParseRule::new(None, Some(Parser::binary), Precedence::Factor),
+        TokenType::Star => ParseRule::new(None, Some(Parser::binary), Precedence::Factor),
+        TokenType::Bang => ParseRule::new(Some(Parser::unary), None, Precedence::None),
+        TokenType::BangEqual | TokenType::EqualEqual => {
+            ParseRule::new(None, Some(Parser::binary), Precedence::Equality)
+        }
+        TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => {
+            ParseRule::new(None, Some(Parser::binary), Precedence::Comparison)
+        }
+        TokenType::Identifier => ParseRule::new(Some(Parser::variable), None, Precedence::None),
+        TokenType::String => ParseRule::new(Some(Parser::string), None, Precedence::None),
+        TokenType::Interpolation => {
+            ParseRule::new(Some(Parser::interpolation), None, Precedence::None)
+        }
+        TokenType::Number => ParseRule::new(Some(Parser::number), None, Precedence::None),
+        TokenType::Super => ParseRule::new(Some(Parser::super_), None, Precedence::None),
+        TokenType::This => ParseRule::new(Some(Parser::this), None, Precedence::None),
+        TokenType::And => ParseRule::new(None, Some(Parser::and), Precedence::And),
+        TokenType::Or => ParseRule::new(None, Some(Parser::or), Precedence::Or),
+        TokenType::False | TokenType::True | TokenType::Nil => {
+            ParseRule::new(Some(Parser::literal), None, Precedence::None)
+        }
+        _ => ParseRule::new(None, None, Precedence::None),
+    }
+}
+
+/// How tightly `token_type` binds as an infix operator, or `Precedence::None` if it isn't one.
+/// Assignment isn't in the table, because it's parsed as part of its target.
+pub(crate) fn infix_precedence(token_type: TokenType) -> Precedence {
+    get_rule(token_type).precedence
+}
+
+/// A local variable, resolved at compile time to a slot on the VM stack.
+struct Local<'src> {
+    name: Token<'src>,
+    /// Scope depth the variable was declared at. None until its initializer has been compiled.
+    depth: Option<usize>,
+    /// Whether a closure captures this variable, so it must be moved to the heap when it
+    /// goes out of scope.
+    is_captured: bool,
 }
 
-fn end_compiler(parser: &mut Parser) {
-    parser.emit_return();
+/// A variable captured by the function being compiled, from an enclosing function.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Upvalue {
+    /// Index into the enclosing function's locals if `is_local`, otherwise into its upvalues.
+    index: u8,
+    is_local: bool,
 }
 
-struct Parser<'src, 'chunk> {
-    current_chunk: &'chunk mut Chunk,
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FunctionType {
+    Function,
+    /// A class's `init` method, which always returns the new instance.
+    Initializer,
+    Method,
+    /// The implicit function wrapping a program's top-level code.
+    Script,
+}
+
+/// State for the function currently being compiled: its bytecode, and which locals are in scope.
+struct Compiler<'src> {
+    function: ObjFunction,
+    function_type: FunctionType,
+    /// Mirrors the function's stack window: the local at index i lives in slot i.
+    locals: Vec<Local<'src>>,
+    upvalues: Vec<Upvalue>,
+    /// How many blocks deep the compiler currently is. Zero is global scope.
+    scope_depth: usize,
+    /// Where each constant already in the function's chunk lives, so equal constants share a slot.
+    constant_indices: HashMap<ConstantKey, usize>,
+}
+
+/// Identifies equal constants. Numbers compare by bit pattern, so 0 and -0 get separate slots
+/// and NaN can share one. Strings are interned, so equal strings have equal handles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    Obj(ObjRef),
+}
+
+impl ConstantKey {
+    fn new(value: Value) -> Option<Self> {
+        match value {
+            Value::Number(n) => Some(Self::Number(n.to_bits())),
+            Value::Obj(obj) => Some(Self::Obj(obj)),
+            Value::Nil | Value::Bool(_) => None,
+        }
+    }
+}
+
+impl<'src> Compiler<'src> {
+    fn new(function_type: FunctionType, name: Option<ObjRef>) -> Self {
+        Self {
+            function: ObjFunction::new(name),
+            function_type,
+            // Slot zero holds the function being called, which methods see as `this`.
+            // Other functions give it an empty name, so the user can't refer to it.
+            locals: vec![Local {
+                name: Token::new(
+                    TokenType::Identifier,
+                    0,
+                    match function_type {
+                        FunctionType::Method | FunctionType::Initializer => "this",
+                        FunctionType::Function | FunctionType::Script => "",
+                    },
+                ),
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            constant_indices: HashMap::new(),
+        }
+    }
+}
+
+/// State for each class declaration being compiled, innermost last.
+struct ClassCompiler {
+    /// Whether `super` can be used in this class's methods.
+    has_superclass: bool,
+}
+
+/// Stack slots are addressed with a one-byte operand.
+const MAX_LOCALS: usize = u8::MAX as usize + 1;
+/// Upvalues are addressed with a one-byte operand.
+const MAX_UPVALUES: usize = u8::MAX as usize + 1;
+/// Argument counts are a one-byte operand.
+const MAX_ARGS: usize = u8::MAX as usize;
+/// OP_CONSTANT_LONG and OP_WIDE address constants with a three-byte operand.
+const MAX_CONSTANTS: usize = 1 << 24;
+
+struct Parser<'src, 'heap> {
+    /// Strings and functions are allocated on the VM's heap as they are compiled.
+    vm: &'heap mut Vm,
     scanner: Scanner<'src>,
     current: Option<Token<'src>>,
     previous: Option<Token<'src>>,
+    /// One compiler per function being compiled. The innermost function is last.
+    compilers: Vec<Compiler<'src>>,
+    /// Classes being compiled, innermost last. Empty outside of a class body.
+    classes: Vec<ClassCompiler>,
+    /// Every error found so far.
+    errors: Vec<CompileErr>,
+    /// Set after an error, until the parser reaches a statement boundary and can resume.
+    panic_mode: bool,
 }
 
-impl<'src, 'chunk> Parser<'src, 'chunk> {
-    fn advance(&mut self) -> Result<(), CompileErr> {
+impl<'src, 'heap> Parser<'src, 'heap> {
+    fn compiler(&self) -> &Compiler<'src> {
+        self.compilers.last().expect("there is always a compiler")
+    }
+
+    fn compiler_mut(&mut self) -> &mut Compiler<'src> {
+        self.compilers
+            .last_mut()
+            .expect("there is always a compiler")
+    }
+
+    /// Allocate an object on the VM's heap. If that triggers a garbage collection, the
+    /// constants of every function still being compiled are kept alive.
+    fn alloc(&mut self, object: Object) -> ObjRef {
+        if self.vm.heap.should_collect() {
+            self.vm.heap.mark_references(&object);
+            self.collect_garbage();
+        }
+        self.vm.heap.alloc(object)
+    }
+
+    fn intern(&mut self, chars: &str) -> ObjRef {
+        if self.vm.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.vm.heap.intern(chars)
+    }
+
+    fn collect_garbage(&mut self) {
+        for compiler in &self.compilers {
+            if let Some(name) = compiler.function.name {
+                self.vm.heap.mark_object(name);
+            }
+            for &constant in &compiler.function.chunk.constants {
+                self.vm.heap.mark_value(constant);
+            }
+        }
+        self.vm.collect_garbage();
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.compiler_mut().function.chunk
+    }
+
+    /// Finish compiling the innermost function. Returns it, along with the variables it captures.
+    fn end_compiler(&mut self) -> (ObjFunction, Vec<Upvalue>) {
+        self.emit_return();
+        let compiler = self.compilers.pop().expect("there is always a compiler");
+        let function = compiler.function;
+        if cfg!(feature = "trace") {
+            let name = match function.name {
+                Some(name) => self.vm.heap.as_str(name).unwrap_or_default(),
+                None => "<script>",
+            };
+            function.chunk.disassemble(name, &self.vm.heap);
+        }
+        (function, compiler.upvalues)
+    }
+
+    /// Move on to the next valid token, reporting any invalid ones along the way.
+    fn advance(&mut self) {
         self.previous = self.current;
         loop {
             let curr = self.scanner.scan_token();
-            let tt = curr.token_type;
             self.current = Some(curr);
-            if tt != TokenType::Error {
+            let TokenType::Error(msg) = curr.token_type else {
                 break;
-            }
-            // errorAtCurrent(self.current.start)
-            return Err(CompileErr::BadToken {
-                line: self.scanner.line,
+            };
+            self.report(CompileErr::BadToken {
+                span: curr.span(),
+                msg,
             });
         }
-        Ok(())
     }
 
-    /// Read the next token, validate it has the expected type.
-    fn consume(&self, expected: TokenType, msg: &'static str) -> Result<(), CompileErr> {
-        match self.current {
-            Some(Token { token_type, .. }) if token_type == expected => Ok(()),
-            _ => Err(CompileErr::Other {
-                line: self.scanner.line,
-                msg,
-            }),
+    /// Read the next token, validate it has the expected type. A missing token is reported
+    /// rather than returned, so the parser carries on as if it were there.
+    fn consume(&mut self, expected: TokenType, msg: &'static str) {
+        if self.check(expected) {
+            self.advance();
+        } else {
+            self.report(self.error_at_current(msg));
+        }
+    }
+
+    fn check(&self, expected: TokenType) -> bool {
+        self.current_type() == expected
+    }
+
+    /// If the current token has the expected type, consume it and return true.
+    fn match_token(&mut self, expected: TokenType) -> bool {
+        if !self.check(expected) {
+            return false;
+        }
+        self.advance();
+        true
+    }
+
+    fn error_at_current(&self, msg: &'static str) -> CompileErr {
+        CompileErr::Other {
+            span: self.current.map(|t| t.span()).unwrap_or_default(),
+            msg,
         }
     }
+
+    fn error(&self, msg: &'static str) -> CompileErr {
+        CompileErr::Other {
+            span: self.previous.map(|t| t.span()).unwrap_or_default(),
+            msg,
+        }
+    }
+
+    /// Record an error, unless the parser is already recovering from an earlier one. Errors
+    /// found while recovering are usually just knock-on effects of the first.
+    fn report(&mut self, err: CompileErr) {
+        if !self.panic_mode {
+            self.panic_mode = true;
+            self.errors.push(err);
+        }
+    }
+
+    /// Skip tokens until something that looks like the start of a statement, so parsing can
+    /// resume after an error.
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+        while !self.check(TokenType::Eof) {
+            if self.previous().token_type == TokenType::Semicolon {
+                return;
+            }
+            match self.current_type() {
+                TokenType::Class
+                | TokenType::Fun
+                | TokenType::Var
+                | TokenType::For
+                | TokenType::If
+                | TokenType::While
+                | TokenType::Print
+                | TokenType::Return => return,
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn previous(&self) -> Token<'src> {
+        self.previous
+            .expect("parser has consumed at least one token")
+    }
+
+    fn current_type(&self) -> TokenType {
+        self.current
+            .map(|t| t.token_type)
+            .expect("parser has been started")
+    }
+
     fn emit_byte(&mut self, byte: u8) {
-        self.current_chunk
-            .write(byte, self.previous.map(|t| t.line).unwrap());
+        let line = self.previous.map(|t| t.line).unwrap();
+        self.current_chunk().write(byte, line);
     }
 
     fn emit_bytes(&mut self, a: u8, b: u8) {
@@ -71,7 +546,1201 @@ impl<'src, 'chunk> Parser<'src, 'chunk> {
         self.emit_byte(b);
     }
 
+    /// Emit a jump with a placeholder offset. Returns the offset's position in the
+    /// chunk, so it can be filled in by [`Parser::patch_jump`].
+    fn emit_jump(&mut self, instruction: Opcode) -> usize {
+        self.emit_byte(instruction as u8);
+        self.emit_bytes(0xff, 0xff);
+        self.current_chunk().code.len() - 2
+    }
+
+    /// Backpatch a jump emitted earlier so it lands on the next instruction to be emitted.
+    fn patch_jump(&mut self, offset: usize) -> Result<(), CompileErr> {
+        // -2 to adjust for the bytecode for the jump offset itself.
+        let jump = self.current_chunk().code.len() - offset - 2;
+        let Ok(jump) = u16::try_from(jump) else {
+            return Err(self.error("Too much code to jump over."));
+        };
+        self.current_chunk().code[offset..offset + 2].copy_from_slice(&jump.to_be_bytes());
+        Ok(())
+    }
+
+    /// Emit a backwards jump to `loop_start`.
+    fn emit_loop(&mut self, loop_start: usize) -> Result<(), CompileErr> {
+        self.emit_byte(Opcode::Loop as u8);
+        // +2 to also jump back over the loop instruction's own operand.
+        let offset = self.current_chunk().code.len() - loop_start + 2;
+        let Ok(offset) = u16::try_from(offset) else {
+            return Err(self.error("Loop body too large."));
+        };
+        let [hi, lo] = offset.to_be_bytes();
+        self.emit_bytes(hi, lo);
+        Ok(())
+    }
+
     fn emit_return(&mut self) {
+        if self.compiler().function_type == FunctionType::Initializer {
+            // Initializers return the new instance, which is in slot zero.
+            self.emit_bytes(Opcode::GetLocal as u8, 0);
+        } else {
+            // A function without a return statement implicitly returns nil.
+            self.emit_byte(Opcode::Nil as u8);
+        }
         self.emit_byte(Opcode::Return as u8);
     }
+
+    fn emit_constant(&mut self, value: Value) -> Result<(), CompileErr> {
+        let constant = self.add_constant(value)?;
+        if let Ok(constant) = u8::try_from(constant) {
+            self.emit_bytes(Opcode::Constant as u8, constant);
+        } else {
+            let [_, hi, mid, lo] = (constant as u32).to_be_bytes();
+            self.emit_byte(Opcode::ConstantLong as u8);
+            self.emit_bytes(hi, mid);
+            self.emit_byte(lo);
+        }
+        Ok(())
+    }
+
+    /// Emit an instruction with an index operand, like a constant or a stack slot. Only
+    /// constants go past 255; those get an OP_WIDE prefix and a three-byte operand.
+    fn emit_with_index(&mut self, instruction: Opcode, index: usize) {
+        if let Ok(index) = u8::try_from(index) {
+            self.emit_bytes(instruction as u8, index);
+        } else {
+            let [_, hi, mid, lo] = (index as u32).to_be_bytes();
+            self.emit_bytes(Opcode::Wide as u8, instruction as u8);
+            self.emit_bytes(hi, mid);
+            self.emit_byte(lo);
+        }
+    }
+
+    fn add_constant(&mut self, value: Value) -> Result<usize, CompileErr> {
+        let key = ConstantKey::new(value);
+        if let Some(&index) = key.and_then(|key| self.compiler().constant_indices.get(&key)) {
+            return Ok(index);
+        }
+        if self.current_chunk().constants.len() == MAX_CONSTANTS {
+            return Err(self.error("Too many constants in one chunk."));
+        }
+        let index = self.current_chunk().add_constant(value);
+        if let Some(key) = key {
+            self.compiler_mut().constant_indices.insert(key, index);
+        }
+        Ok(index)
+    }
+
+    fn identifier_constant(&mut self, name: Token) -> Result<usize, CompileErr> {
+        let obj = self.intern(name.lexeme);
+        self.add_constant(Value::Obj(obj))
+    }
+
+    fn expression(&mut self) -> Result<(), CompileErr> {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    /// Compile a declaration. Errors are recorded rather than returned, and parsing resumes
+    /// at the next statement.
+    fn declaration(&mut self) {
+        let result = if self.match_token(TokenType::Class) {
+            self.class_declaration()
+        } else if self.match_token(TokenType::Fun) {
+            self.fun_declaration()
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration()
+        } else {
+            self.statement()
+        };
+        if let Err(err) = result {
+            self.report(err);
+        }
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    fn class_declaration(&mut self) -> Result<(), CompileErr> {
+        self.consume(TokenType::Identifier, "Expect class name.");
+        let class_name = self.previous();
+        let name_constant = self.identifier_constant(class_name)?;
+        self.declare_variable()?;
+
+        self.emit_with_index(Opcode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+        let result = self.class_body(class_name);
+        // Clean up even after an error, so the parser can carry on.
+        let class = self.classes.pop().expect("a class is being compiled");
+        if class.has_superclass {
+            self.end_scope();
+        }
+        result
+    }
+
+    /// Compile a class's superclass clause and methods.
+    fn class_body(&mut self, class_name: Token<'src>) -> Result<(), CompileErr> {
+        if self.match_token(TokenType::Less) {
+            self.consume(TokenType::Identifier, "Expect superclass name.");
+            self.variable(false)?;
+            if class_name.lexeme == self.previous().lexeme {
+                return Err(self.error("A class can't inherit from itself."));
+            }
+
+            // Keep the superclass in a local named `super`, so methods can capture it.
+            // Each class gets its own scope, so sibling classes don't clash.
+            self.begin_scope();
+            self.classes
+                .last_mut()
+                .expect("a class is being compiled")
+                .has_superclass = true;
+            self.add_local(Token::new(TokenType::Super, class_name.line, "super"))?;
+            self.define_variable(0);
+
+            self.named_variable(class_name, false)?;
+            self.emit_byte(Opcode::Inherit as u8);
+        }
+
+        // Load the class back onto the stack, so methods can be attached to it.
+        self.named_variable(class_name, false)?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.method()?;
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(Opcode::Pop as u8);
+        Ok(())
+    }
+
+    fn method(&mut self) -> Result<(), CompileErr> {
+        self.consume(TokenType::Identifier, "Expect method name.");
+        let name = self.previous();
+        let constant = self.identifier_constant(name)?;
+        let function_type = if name.lexeme == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type)?;
+        self.emit_with_index(Opcode::Method, constant);
+        Ok(())
+    }
+
+    fn fun_declaration(&mut self) -> Result<(), CompileErr> {
+        let global = self.parse_variable("Expect function name.")?;
+        // A function may refer to itself, so it's usable before its body is compiled.
+        self.mark_initialized();
+        self.function(FunctionType::Function)?;
+        self.define_variable(global);
+        Ok(())
+    }
+
+    /// Compile a function's parameters and body, then emit it as a constant.
+    fn function(&mut self, function_type: FunctionType) -> Result<(), CompileErr> {
+        let name = self.intern(self.previous().lexeme);
+        self.compilers
+            .push(Compiler::new(function_type, Some(name)));
+        self.begin_scope();
+        let result = self.function_body();
+
+        // No end_scope() needed: the whole stack window is discarded when the function returns.
+        // Finish the function even after an error, so the parser can carry on.
+        let (function, upvalues) = self.end_compiler();
+        result?;
+        let function = self.alloc(Object::Function(function));
+        let constant = self.add_constant(Value::Obj(function))?;
+        self.emit_with_index(Opcode::Closure, constant);
+        // Tell the VM where to find each captured variable when it creates the closure.
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index);
+        }
+        Ok(())
+    }
+
+    fn function_body(&mut self) -> Result<(), CompileErr> {
+        // Compile the body even after a malformed signature, so that its statements are
+        // checked in the right function rather than mistaken for the enclosing code.
+        if let Err(err) = self.signature() {
+            self.report(err);
+        }
+        self.block()
+    }
+
+    /// Compile a function's parameter list, up to the brace that opens its body.
+    fn signature(&mut self) -> Result<(), CompileErr> {
+        self.consume(TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.compiler_mut().function.arity += 1;
+                if self.compiler().function.arity > MAX_ARGS {
+                    return Err(self.error_at_current("Can't have more than 255 parameters."));
+                }
+                let constant = self.parse_variable("Expect parameter name.")?;
+                self.define_variable(constant);
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body.");
+        Ok(())
+    }
+
+    fn var_declaration(&mut self) -> Result<(), CompileErr> {
+        let global = self.parse_variable("Expect variable name.")?;
+        let result = self.var_initializer();
+        // Define the variable even after an error, so later uses of it aren't errors too.
+        self.define_variable(global);
+        result
+    }
+
+    fn var_initializer(&mut self) -> Result<(), CompileErr> {
+        if self.match_token(TokenType::Equal) {
+            self.expression()?;
+        } else {
+            self.emit_byte(Opcode::Nil as u8);
+        }
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+        Ok(())
+    }
+
+    /// Consume a variable name and declare it. For globals, returns the constant
+    /// index of its name. Locals don't need a constant, so they return 0.
+    fn parse_variable(&mut self, msg: &'static str) -> Result<usize, CompileErr> {
+        self.consume(TokenType::Identifier, msg);
+
+        self.declare_variable()?;
+        if self.compiler().scope_depth > 0 {
+            return Ok(0);
+        }
+
+        self.identifier_constant(self.previous())
+    }
+
+    /// Record a new local variable in the current scope. Globals are late-bound, so
+    /// there's nothing to do for them.
+    fn declare_variable(&mut self) -> Result<(), CompileErr> {
+        if self.compiler().scope_depth == 0 {
+            return Ok(());
+        }
+
+        let name = self.previous();
+        let scope_depth = self.compiler().scope_depth;
+        let already_declared = self
+            .compiler()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name.lexeme == name.lexeme);
+        if already_declared {
+            return Err(self.error("Already a variable with this name in this scope."));
+        }
+        self.add_local(name)
+    }
+
+    fn add_local(&mut self, name: Token<'src>) -> Result<(), CompileErr> {
+        if self.compiler().locals.len() == MAX_LOCALS {
+            return Err(self.error("Too many local variables in function."));
+        }
+        self.compiler_mut().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+        Ok(())
+    }
+
+    /// Mark the most recently declared local as initialized, so it can be read.
+    fn mark_initialized(&mut self) {
+        let compiler = self.compiler_mut();
+        if let Some(local) = compiler.locals.last_mut() {
+            local.depth = Some(compiler.scope_depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.compiler().scope_depth > 0 {
+            // The initializer's value is already sitting in the local's stack slot.
+            self.mark_initialized();
+            return;
+        }
+        self.emit_with_index(Opcode::DefineGlobal, global);
+    }
+
+    /// Find the stack slot of the innermost local with this name in the given function.
+    fn resolve_local(&self, compiler: usize, name: Token) -> Result<Option<u8>, CompileErr> {
+        let Some((slot, local)) = self.compilers[compiler]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name.lexeme == name.lexeme)
+        else {
+            return Ok(None);
+        };
+        if local.depth.is_none() {
+            return Err(self.error("Can't read local variable in its own initializer."));
+        }
+        Ok(Some(slot as u8))
+    }
+
+    /// Find a variable with this name in the functions enclosing the given one, and capture it.
+    /// Returns the index of the new (or existing) upvalue.
+    fn resolve_upvalue(&mut self, compiler: usize, name: Token) -> Result<Option<u8>, CompileErr> {
+        if compiler == 0 {
+            return Ok(None);
+        }
+        let enclosing = compiler - 1;
+
+        if let Some(local) = self.resolve_local(enclosing, name)? {
+            self.compilers[enclosing].locals[local as usize].is_captured = true;
+            return self.add_upvalue(compiler, local, true).map(Some);
+        }
+        if let Some(upvalue) = self.resolve_upvalue(enclosing, name)? {
+            return self.add_upvalue(compiler, upvalue, false).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn add_upvalue(
+        &mut self,
+        compiler: usize,
+        index: u8,
+        is_local: bool,
+    ) -> Result<u8, CompileErr> {
+        let upvalue = Upvalue { index, is_local };
+        let upvalues = &self.compilers[compiler].upvalues;
+        // A function that refers to the same variable twice only captures it once.
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return Ok(existing as u8);
+        }
+        if upvalues.len() == MAX_UPVALUES {
+            return Err(self.error("Too many closure variables in function."));
+        }
+
+        let compiler = &mut self.compilers[compiler];
+        compiler.upvalues.push(upvalue);
+        compiler.function.upvalue_count = compiler.upvalues.len();
+        Ok((compiler.upvalues.len() - 1) as u8)
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler_mut().scope_depth += 1;
+    }
+
+    /// Leave a block, discarding the locals declared inside it.
+    fn end_scope(&mut self) {
+        self.compiler_mut().scope_depth -= 1;
+        loop {
+            let compiler = self.compiler();
+            let Some(local) = compiler.locals.last() else {
+                break;
+            };
+            if local
+                .depth
+                .is_none_or(|depth| depth <= compiler.scope_depth)
+            {
+                break;
+            }
+            // Captured variables move to the heap, the rest are just popped off the stack.
+            if local.is_captured {
+                self.emit_byte(Opcode::CloseUpvalue as u8);
+            } else {
+                self.emit_byte(Opcode::Pop as u8);
+            }
+            self.compiler_mut().locals.pop();
+        }
+    }
+
+    fn statement(&mut self) -> Result<(), CompileErr> {
+        if self.match_token(TokenType::Print) {
+            self.print_statement()
+        } else if self.match_token(TokenType::Return) {
+            self.return_statement()
+        } else if self.match_token(TokenType::For) {
+            self.for_statement()
+        } else if self.match_token(TokenType::If) {
+            self.if_statement()
+        } else if self.match_token(TokenType::While) {
+            self.while_statement()
+        } else if self.match_token(TokenType::LeftBrace) {
+            self.scoped(Self::block)
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    /// Compile `f` in a new block scope. The scope is closed even if `f` fails, so the
+    /// parser can carry on resolving variables after an error.
+    fn scoped(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<(), CompileErr>,
+    ) -> Result<(), CompileErr> {
+        self.begin_scope();
+        let result = f(self);
+        self.end_scope();
+        result
+    }
+
+    fn block(&mut self) -> Result<(), CompileErr> {
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            self.declaration();
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+        Ok(())
+    }
+
+    fn if_statement(&mut self) -> Result<(), CompileErr> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(Opcode::JumpIfFalse);
+        self.emit_byte(Opcode::Pop as u8);
+        self.statement()?;
+
+        let else_jump = self.emit_jump(Opcode::Jump);
+        self.patch_jump(then_jump)?;
+        self.emit_byte(Opcode::Pop as u8);
+
+        if self.match_token(TokenType::Else) {
+            self.statement()?;
+        }
+        self.patch_jump(else_jump)
+    }
+
+    fn while_statement(&mut self) -> Result<(), CompileErr> {
+        let loop_start = self.current_chunk().code.len();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(Opcode::JumpIfFalse);
+        self.emit_byte(Opcode::Pop as u8);
+        self.statement()?;
+        self.emit_loop(loop_start)?;
+
+        self.patch_jump(exit_jump)?;
+        self.emit_byte(Opcode::Pop as u8);
+        Ok(())
+    }
+
+    fn for_statement(&mut self) -> Result<(), CompileErr> {
+        // The initializer's variable is scoped to the loop.
+        self.scoped(Self::for_loop)
+    }
+
+    fn for_loop(&mut self) -> Result<(), CompileErr> {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.match_token(TokenType::Semicolon) {
+            // No initializer.
+        } else if self.match_token(TokenType::Var) {
+            self.var_declaration()?;
+        } else {
+            self.expression_statement()?;
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::Semicolon) {
+            self.expression()?;
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            // Jump out of the loop if the condition is false.
+            exit_jump = Some(self.emit_jump(Opcode::JumpIfFalse));
+            self.emit_byte(Opcode::Pop as u8);
+        }
+
+        if !self.match_token(TokenType::RightParen) {
+            // The increment is compiled before the body but runs after it, so jump
+            // over it to the body, then have the body loop back to it.
+            let body_jump = self.emit_jump(Opcode::Jump);
+            let increment_start = self.current_chunk().code.len();
+            self.expression()?;
+            self.emit_byte(Opcode::Pop as u8);
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start)?;
+            loop_start = increment_start;
+            self.patch_jump(body_jump)?;
+        }
+
+        self.statement()?;
+        self.emit_loop(loop_start)?;
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump)?;
+            self.emit_byte(Opcode::Pop as u8); // Condition.
+        }
+        Ok(())
+    }
+
+    fn print_statement(&mut self) -> Result<(), CompileErr> {
+        self.expression()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        self.emit_byte(Opcode::Print as u8);
+        Ok(())
+    }
+
+    fn return_statement(&mut self) -> Result<(), CompileErr> {
+        if self.compiler().function_type == FunctionType::Script {
+            return Err(self.error("Can't return from top-level code."));
+        }
+
+        if self.match_token(TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.compiler().function_type == FunctionType::Initializer {
+                return Err(self.error("Can't return a value from an initializer."));
+            }
+            self.expression()?;
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_byte(Opcode::Return as u8);
+        }
+        Ok(())
+    }
+
+    /// Evaluate an expression for its side effects, discarding the result.
+    fn expression_statement(&mut self) -> Result<(), CompileErr> {
+        self.expression()?;
+        self.consume(TokenType::Semicolon, "Expect ';' after expression.");
+        self.emit_byte(Opcode::Pop as u8);
+        Ok(())
+    }
+
+    /// Parse any expression whose operators bind at least as tightly as `precedence`.
+    fn parse_precedence(&mut self, precedence: Precedence) -> Result<(), CompileErr> {
+        self.advance();
+        let Some(prefix_rule) = get_rule(self.previous().token_type).prefix else {
+            return Err(self.error("Expect expression."));
+        };
+        // Only a low-precedence context may treat a following `=` as assignment,
+        // otherwise `a * b = c` would parse as `a * (b = c)`.
+        let can_assign = precedence <= Precedence::Assignment;
+        prefix_rule(self, can_assign)?;
+
+        while precedence <= get_rule(self.current_type()).precedence {
+            self.advance();
+            let infix_rule = get_rule(self.previous().token_type)
+                .infix
+                .expect("every token with a precedence has an infix rule");
+            infix_rule(self, can_assign)?;
+        }
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            return Err(self.error("Invalid assignment target."));
+        }
+        Ok(())
+    }
+
+    fn number(&mut self, _can_assign: bool) -> Result<(), CompileErr> {
+        let value = parse_number(self.previous().lexeme).map_err(|msg| self.error(msg))?;
+        self.emit_constant(Value::from(value))
+    }
+
+    fn string(&mut self, _can_assign: bool) -> Result<(), CompileErr> {
+        let chars = string_contents(self.previous())?;
+        let obj = self.intern(&chars);
+        self.emit_constant(Value::Obj(obj))
+    }
+
+    /// A string with expressions interpolated into it, like `"Hello ${name}!"`. Each
+    /// expression is converted to a string, then all the pieces are concatenated.
+    fn interpolation(&mut self, _can_assign: bool) -> Result<(), CompileErr> {
+        let mut has_prefix = self.string_segment()?;
+        loop {
+            self.expression()?;
+            self.emit_byte(Opcode::ToString as u8);
+            if has_prefix {
+                self.emit_byte(Opcode::Add as u8);
+            }
+            has_prefix = true;
+
+            let more = self.match_token(TokenType::InterpolationMid);
+            // The rest of the string is in the closing segment, so there's no carrying on
+            // without it.
+            if !more && !self.match_token(TokenType::InterpolationEnd) {
+                return Err(self.error_at_current("Expect '}' after interpolated expression."));
+            }
+            if self.string_segment()? {
+                self.emit_byte(Opcode::Add as u8);
+            }
+            if !more {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Push the part of an interpolated string in the previous token, unless it's empty.
+    /// Returns whether anything was pushed.
+    fn string_segment(&mut self) -> Result<bool, CompileErr> {
+        let chars = string_contents(self.previous())?;
+        if chars.is_empty() {
+            return Ok(false);
+        }
+        let obj = self.intern(&chars);
+        self.emit_constant(Value::Obj(obj))?;
+        Ok(true)
+    }
+
+    fn call(&mut self, _can_assign: bool) -> Result<(), CompileErr> {
+        let arg_count = self.argument_list()?;
+        self.emit_bytes(Opcode::Call as u8, arg_count);
+        Ok(())
+    }
+
+    /// Compile comma-separated arguments up to the closing paren. Returns how many there were.
+    fn argument_list(&mut self) -> Result<u8, CompileErr> {
+        let mut arg_count = 0usize;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                self.expression()?;
+                if arg_count == MAX_ARGS {
+                    return Err(self.error("Can't have more than 255 arguments."));
+                }
+                arg_count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+        Ok(arg_count as u8)
+    }
+
+    fn dot(&mut self, can_assign: bool) -> Result<(), CompileErr> {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(self.previous())?;
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression()?;
+            self.emit_with_index(Opcode::SetProperty, name);
+        } else {
+            self.emit_with_index(Opcode::GetProperty, name);
+        }
+        Ok(())
+    }
+
+    fn super_(&mut self, _can_assign: bool) -> Result<(), CompileErr> {
+        match self.classes.last() {
+            None => return Err(self.error("Can't use 'super' outside of a class.")),
+            Some(ClassCompiler {
+                has_superclass: false,
+            }) => return Err(self.error("Can't use 'super' in a class with no superclass.")),
+            Some(_) => {}
+        }
+
+        self.consume(TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(TokenType::Identifier, "Expect superclass method name.");
+        let name = self.identifier_constant(self.previous())?;
+
+        // Bind the superclass's method to the current receiver.
+        let line = self.previous().line;
+        self.named_variable(Token::new(TokenType::This, line, "this"), false)?;
+        self.named_variable(Token::new(TokenType::Super, line, "super"), false)?;
+        self.emit_with_index(Opcode::GetSuper, name);
+        Ok(())
+    }
+
+    fn this(&mut self, _can_assign: bool) -> Result<(), CompileErr> {
+        if self.classes.is_empty() {
+            return Err(self.error("Can't use 'this' outside of a class."));
+        }
+        // `this` is an ordinary local in slot zero, and can't be assigned to.
+        self.variable(false)
+    }
+
+    fn variable(&mut self, can_assign: bool) -> Result<(), CompileErr> {
+        self.named_variable(self.previous(), can_assign)
+    }
+
+    fn named_variable(&mut self, name: Token, can_assign: bool) -> Result<(), CompileErr> {
+        let current = self.compilers.len() - 1;
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(current, name)? {
+            (Opcode::GetLocal, Opcode::SetLocal, usize::from(slot))
+        } else if let Some(upvalue) = self.resolve_upvalue(current, name)? {
+            (Opcode::GetUpvalue, Opcode::SetUpvalue, usize::from(upvalue))
+        } else {
+            let global = self.identifier_constant(name)?;
+            (Opcode::GetGlobal, Opcode::SetGlobal, global)
+        };
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression()?;
+            self.emit_with_index(set_op, arg);
+        } else {
+            self.emit_with_index(get_op, arg);
+        }
+        Ok(())
+    }
+
+    /// Short-circuit: if the left operand is falsey, it's the result and the right is skipped.
+    fn and(&mut self, _can_assign: bool) -> Result<(), CompileErr> {
+        let end_jump = self.emit_jump(Opcode::JumpIfFalse);
+        self.emit_byte(Opcode::Pop as u8);
+        self.parse_precedence(Precedence::And)?;
+        self.patch_jump(end_jump)
+    }
+
+    /// Short-circuit: if the left operand is truthy, it's the result and the right is skipped.
+    fn or(&mut self, _can_assign: bool) -> Result<(), CompileErr> {
+        let else_jump = self.emit_jump(Opcode::JumpIfFalse);
+        let end_jump = self.emit_jump(Opcode::Jump);
+
+        self.patch_jump(else_jump)?;
+        self.emit_byte(Opcode::Pop as u8);
+
+        self.parse_precedence(Precedence::Or)?;
+        self.patch_jump(end_jump)
+    }
+
+    fn literal(&mut self, _can_assign: bool) -> Result<(), CompileErr> {
+        match self.previous().token_type {
+            TokenType::False => self.emit_byte(Opcode::False as u8),
+            TokenType::Nil => self.emit_byte(Opcode::Nil as u8),
+            TokenType::True => self.emit_byte(Opcode::True as u8),
+            _ => unreachable!("literal() is only registered for literal keywords"),
+        }
+        Ok(())
+    }
+
+    fn grouping(&mut self, _can_assign: bool) -> Result<(), CompileErr> {
+        self.expression()?;
+        self.consume(TokenType::RightParen, "Expect ')' after expression.");
+        Ok(())
+    }
+
+    fn unary(&mut self, _can_assign: bool) -> Result<(), CompileErr> {
+        let operator_type = self.previous().token_type;
+
+        // Compile the operand.
+        self.parse_precedence(Precedence::Unary)?;
+
+        // Emit the operator instruction.
+        match operator_type {
+            TokenType::Bang => self.emit_byte(Opcode::Not as u8),
+            TokenType::Minus => self.emit_byte(Opcode::Negate as u8),
+            _ => unreachable!("unary() is only registered for unary operators"),
+        }
+        Ok(())
+    }
+
+    fn binary(&mut self, _can_assign: bool) -> Result<(), CompileErr> {
+        let operator_type = self.previous().token_type;
+        let rule = get_rule(operator_type);
+        self.parse_precedence(rule.precedence.next())?;
+
+        match operator_type {
+            TokenType::BangEqual => self.emit_bytes(Opcode::Equal as u8, Opcode::Not as u8),
+            TokenType::EqualEqual => self.emit_byte(Opcode::Equal as u8),
+            TokenType::Greater => self.emit_byte(Opcode::Greater as u8),
+            TokenType::GreaterEqual => self.emit_bytes(Opcode::Less as u8, Opcode::Not as u8),
+            TokenType::Less => self.emit_byte(Opcode::Less as u8),
+            TokenType::LessEqual => self.emit_bytes(Opcode::Greater as u8, Opcode::Not as u8),
+            TokenType::Plus => self.emit_byte(Opcode::Add as u8),
+            TokenType::Minus => self.emit_byte(Opcode::Sub as u8),
+            TokenType::Star => self.emit_byte(Opcode::Mul as u8),
+            TokenType::Slash => self.emit_byte(Opcode::Div as u8),
+            _ => unreachable!("binary() is only registered for binary operators"),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compile a script, returning its bytecode.
+    fn compile_ok(source: &str) -> Vec<u8> {
+        let mut vm = Vm::new();
+        let script = compile(source, &mut vm).unwrap();
+        vm.heap.as_function(script).unwrap().chunk.code.clone()
+    }
+
+    /// Compile a script that should have exactly one error, and return it.
+    fn compile_err(source: &str) -> CompileErr {
+        let mut errors = compile(source, &mut Vm::new()).unwrap_err();
+        assert_eq!(errors.len(), 1, "{errors:?}");
+        errors.remove(0)
+    }
+
+    #[test]
+    fn precedence_of_arithmetic() {
+        let code = compile_ok("-(1 + 2) * 3 - 4 / 5;");
+        let constant = Opcode::Constant as u8;
+        let expected = vec![
+            constant,
+            0,
+            constant,
+            1,
+            Opcode::Add as u8,
+            Opcode::Negate as u8,
+            constant,
+            2,
+            Opcode::Mul as u8,
+            constant,
+            3,
+            constant,
+            4,
+            Opcode::Div as u8,
+            Opcode::Sub as u8,
+            Opcode::Pop as u8,
+            Opcode::Nil as u8,
+            Opcode::Return as u8,
+        ];
+        assert_eq!(code, expected);
+    }
+
+    #[test]
+    fn comparisons_desugar_to_not() {
+        let code = compile_ok("print !(1 <= 2) != nil;");
+        let expected = vec![
+            Opcode::Constant as u8,
+            0,
+            Opcode::Constant as u8,
+            1,
+            Opcode::Greater as u8,
+            Opcode::Not as u8,
+            Opcode::Not as u8,
+            Opcode::Nil as u8,
+            Opcode::Equal as u8,
+            Opcode::Not as u8,
+            Opcode::Print as u8,
+            Opcode::Nil as u8,
+            Opcode::Return as u8,
+        ];
+        assert_eq!(code, expected);
+    }
+
+    #[test]
+    fn string_literals_are_interned() {
+        let mut vm = Vm::new();
+        let script = compile(r#""ab" + "ab";"#, &mut vm).unwrap();
+        let constants = &vm.heap.as_function(script).unwrap().chunk.constants;
+        assert_eq!(constants.len(), 1);
+        let Value::Obj(obj) = constants[0] else {
+            panic!("expected a string constant");
+        };
+        assert_eq!(vm.heap.as_str(obj), Some("ab"));
+    }
+
+    #[test]
+    fn number_literals_are_exact() {
+        let parsed: Vec<_> = [
+            "0xFF",
+            "0b1010",
+            "0o17",
+            "1_000_000",
+            "1.5e-3",
+            "0x1_0000_0000_0001",
+        ]
+        .into_iter()
+        .map(|lexeme| parse_number(lexeme).unwrap())
+        .collect();
+        assert_eq!(parsed, [255.0, 10.0, 15.0, 1e6, 0.0015, 281474976710657.0]);
+        // 2^64 + 1 rounds to the nearest f64, 2^64.
+        assert_eq!(
+            parse_number("0x1_0000_0000_0000_0001"),
+            Ok(18446744073709551616.0)
+        );
+        assert!(parse_number("1e400").is_err());
+        assert!(parse_number(&format!("0x{}", "F".repeat(33))).is_err());
+    }
+
+    #[test]
+    fn string_escapes_are_decoded() {
+        let mut vm = Vm::new();
+        let source = r#"
+            var a = "tab\tquote\" slash\\ \u{48}\u{1F600}\n";
+            var b = """{"raw": "\n"}""";
+        "#;
+        let script = compile(source, &mut vm).unwrap();
+        let strings: Vec<_> = vm
+            .heap
+            .as_function(script)
+            .unwrap()
+            .chunk
+            .constants
+            .iter()
+            .filter_map(|&constant| match constant {
+                Value::Obj(obj) => vm.heap.as_str(obj),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            strings,
+            ["a", "tab\tquote\" slash\\ H😀\n", "b", r#"{"raw": "\n"}"#]
+        );
+    }
+
+    #[test]
+    fn invalid_escapes_are_spanned() {
+        let spans: Vec<_> = [r#""ok\q""#, "\"\n  \\u{D800}\"", r#""\u12""#]
+            .into_iter()
+            .map(|source| match compile_err(source) {
+                CompileErr::Other { span, msg } => (span, msg),
+                other => panic!("unexpected error {other:?}"),
+            })
+            .collect();
+        let span = |start, length, line, column| Span {
+            start,
+            length,
+            line,
+            column,
+        };
+        assert_eq!(
+            spans,
+            [
+                (span(3, 2, 1, 4), "Invalid escape sequence."),
+                (span(4, 8, 2, 3), "Invalid unicode escape."),
+                (span(1, 2, 1, 2), "Expect unicode escape like '\\u{1F600}'."),
+            ]
+        );
+    }
+
+    #[test]
+    fn interpolations_need_an_expression() {
+        // The rest of the string isn't an operand, so it can't finish the expression.
+        for source in [r#""${1 +}";"#, r#""${}";"#, r#""a${"x" +}b" "c";"#] {
+            let CompileErr::Other { span, msg } = compile_err(source) else {
+                panic!("unexpected error for {source:?}");
+            };
+            assert_eq!(msg, "Expect expression.");
+            assert!(source[span.start..].starts_with('}'), "{source:?}");
+        }
+    }
+
+    #[test]
+    fn equal_constants_share_a_slot() {
+        let mut vm = Vm::new();
+        let script = compile("1; 0; 1; a; a = 1; 0;", &mut vm).unwrap();
+        let constants = &vm.heap.as_function(script).unwrap().chunk.constants;
+        assert_eq!(constants.len(), 3);
+        assert_eq!(constants[..2], [Value::Number(1.0), Value::Number(0.0)]);
+
+        let key = |n: f64| ConstantKey::new(Value::Number(n));
+        assert_ne!(key(0.0), key(-0.0));
+        assert_eq!(key(f64::NAN), key(f64::NAN));
+    }
+
+    #[test]
+    fn wide_constants_past_256() {
+        let source: Vec<String> = (0..300).map(|i| format!("{i};")).collect();
+        let code = compile_ok(&source.concat());
+        // Each of the first 256 statements is OP_CONSTANT, its index, and OP_POP.
+        let long = &code[256 * 3..];
+        assert_eq!(
+            long[..5],
+            [Opcode::ConstantLong as u8, 0, 1, 0, Opcode::Pop as u8]
+        );
+
+        // Names and functions past the first 256 constants get an OP_WIDE prefix. The 44
+        // statements before `x` use OP_CONSTANT_LONG, so each is five bytes.
+        let code = compile_ok(&(source.concat() + "x;"));
+        assert_eq!(
+            code[256 * 3 + 44 * 5..][..6],
+            [
+                Opcode::Wide as u8,
+                Opcode::GetGlobal as u8,
+                0,
+                1,
+                44,
+                Opcode::Pop as u8
+            ]
+        );
+        compile_ok(&(source.concat() + "fun f() {}"));
+
+        let globals: Vec<String> = (0..129).map(|i| format!("var k{i} = {i};")).collect();
+        compile_ok(&globals.concat());
+    }
+
+    #[test]
+    fn missing_operand_is_an_error() {
+        let err = compile_err("1 +;");
+        assert!(matches!(
+            err,
+            CompileErr::Other {
+                msg: "Expect expression.",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn errors_are_reported_after_recovering() {
+        let source = "
+            var a = ;
+            fun f(a b) { return a; }
+            fun g() {
+                var x = 1
+                print x;
+            }
+            print 1 $;
+        ";
+        let errors = compile(source, &mut Vm::new()).unwrap_err();
+        let lines: Vec<_> = errors.iter().map(|err| err.span().line).collect();
+        assert_eq!(lines, [2, 3, 6, 8]);
+    }
+
+    #[test]
+    fn missing_tokens_are_reported_once() {
+        // Parsing carries on as if the token were there, rather than tripping over the
+        // rest of the statement.
+        for (source, expected) in [
+            ("if (x { print 1; }", "Expect ')' after condition."),
+            ("class A < { m() { return 1; } }", "Expect superclass name."),
+            ("while (x print 1;", "Expect ')' after condition."),
+        ] {
+            let CompileErr::Other { msg, .. } = compile_err(source) else {
+                panic!("unexpected error for {source:?}");
+            };
+            assert_eq!(msg, expected);
+        }
+    }
+
+    #[test]
+    fn invalid_assignment_target() {
+        let err = compile_err("var a; var b; a * b = 1;");
+        assert!(matches!(
+            err,
+            CompileErr::Other {
+                msg: "Invalid assignment target.",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn locals_use_stack_slots() {
+        let code = compile_ok("{ var a = 1; { var b = a; b = 2; } }");
+        let expected = vec![
+            Opcode::Constant as u8,
+            0,
+            Opcode::GetLocal as u8,
+            1,
+            Opcode::Constant as u8,
+            1,
+            Opcode::SetLocal as u8,
+            2,
+            Opcode::Pop as u8,
+            Opcode::Pop as u8,
+            Opcode::Pop as u8,
+            Opcode::Nil as u8,
+            Opcode::Return as u8,
+        ];
+        assert_eq!(code, expected);
+    }
+
+    #[test]
+    fn local_scoping_errors() {
+        let mut vm = Vm::new();
+        let err = compile("{ var a = 1; var a = 2; }", &mut vm).unwrap_err();
+        assert!(matches!(
+            err.as_slice(),
+            [CompileErr::Other {
+                msg: "Already a variable with this name in this scope.",
+                ..
+            }]
+        ));
+        let err = compile("{ var a = a; }", &mut vm).unwrap_err();
+        assert!(matches!(
+            err.as_slice(),
+            [CompileErr::Other {
+                msg: "Can't read local variable in its own initializer.",
+                ..
+            }]
+        ));
+        // Shadowing a variable from an enclosing scope is fine.
+        let source = "{ var a = 1; { var b = a; var a = b; } }";
+        assert!(compile(source, &mut vm).is_ok());
+    }
+
+    #[test]
+    fn jumps_are_backpatched() {
+        let code = compile_ok("while (true) print 1;");
+        let expected = vec![
+            Opcode::True as u8,
+            Opcode::JumpIfFalse as u8,
+            0,
+            7,
+            Opcode::Pop as u8,
+            Opcode::Constant as u8,
+            0,
+            Opcode::Print as u8,
+            Opcode::Loop as u8,
+            0,
+            11,
+            Opcode::Pop as u8,
+            Opcode::Nil as u8,
+            Opcode::Return as u8,
+        ];
+        assert_eq!(code, expected);
+    }
+
+    #[test]
+    fn return_outside_function() {
+        let err = compile_err("return 1;");
+        assert!(matches!(
+            err,
+            CompileErr::Other {
+                msg: "Can't return from top-level code.",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn class_errors() {
+        let err = compile_err("print this;");
+        assert!(matches!(
+            err,
+            CompileErr::Other {
+                msg: "Can't use 'this' outside of a class.",
+                ..
+            }
+        ));
+        let err = compile_err("class A { init() { return 1; } }");
+        assert!(matches!(
+            err,
+            CompileErr::Other {
+                msg: "Can't return a value from an initializer.",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn inheritance_errors() {
+        let err = compile_err("class A < A {}");
+        assert!(matches!(
+            err,
+            CompileErr::Other {
+                msg: "A class can't inherit from itself.",
+                ..
+            }
+        ));
+        let err = compile_err("class A { f() { super.f(); } }");
+        assert!(matches!(
+            err,
+            CompileErr::Other {
+                msg: "Can't use 'super' in a class with no superclass.",
+                ..
+            }
+        ));
+        let err = compile_err("super.f();");
+        assert!(matches!(
+            err,
+            CompileErr::Other {
+                msg: "Can't use 'super' outside of a class.",
+                ..
+            }
+        ));
+    }
 }
@@ -0,0 +1,761 @@
+//! Lays out Lox source code in one canonical style.
+//!
+//! The source is parsed with the compiler's grammar into a [`Doc`], which describes the
+//! output along with the places where lines may be broken. Printing the doc then decides
+//! which of those breaks to take, so that lines fit in [`MAX_WIDTH`] columns.
+
+use std::mem;
+
+use crate::{
+    compiler::{compile, infix_precedence, Precedence},
+    tokenizer::{Scanner, Token, TokenType},
+    vm::{Error, Vm},
+};
+
+/// Lines are wrapped to fit in this many columns, where the grammar allows it.
+const MAX_WIDTH: usize = 80;
+/// Spaces per level of indentation.
+const INDENT: usize = 2;
+
+/// Format Lox source code. Comments are kept, and so are single blank lines between
+/// statements.
+///
+/// Only code that compiles can be formatted, otherwise this returns the compile errors.
+pub fn format(source: &str) -> Result<String, Error> {
+    // Checking the code first means the formatter can trust that it follows the grammar.
+    compile(source, &mut Vm::new()).map_err(Error::Compile)?;
+
+    let mut formatter = Formatter {
+        tokens: attach_comments(source),
+        current: 0,
+        flat: 0,
+    };
+    let lines = formatter.lines(Formatter::declaration);
+    let mut formatted = print(&Doc::Concat(lines));
+    if !formatted.is_empty() {
+        formatted.push('\n');
+    }
+    Ok(formatted)
+}
+
+/// A token, along with the comments around it.
+struct CommentedToken<'src> {
+    token: Token<'src>,
+    /// Comments between the previous token and this one.
+    comments: Vec<Comment<'src>>,
+    /// Comments right after this token, when a `)`, `,` or `;` follows them on the line.
+    after: Vec<&'src str>,
+    /// Comments after this token on the same line, when nothing else follows them.
+    trailing: Vec<&'src str>,
+    /// Whether there's an empty line between this token and whatever comes before it.
+    blank_line_before: bool,
+}
+
+struct Comment<'src> {
+    text: &'src str,
+    /// Whether the comment starts a line.
+    own_line: bool,
+    blank_line_before: bool,
+    /// Whether the comment is followed by a newline, rather than more code.
+    ends_line: bool,
+}
+
+/// Split `source` into tokens, attaching each comment to a token next to it. The last
+/// token is always `Eof`, holding any comments at the end of the source.
+fn attach_comments(source: &str) -> Vec<CommentedToken<'_>> {
+    let trivia: Vec<_> = Scanner::with_trivia(source).collect();
+    let mut tokens: Vec<CommentedToken> = Vec::new();
+    let mut comments = Vec::new();
+    // Newlines since the last token or comment.
+    let mut newlines = 0;
+    for (i, token) in trivia.iter().enumerate() {
+        match token.token_type {
+            TokenType::Whitespace => newlines += token.lexeme.matches('\n').count(),
+            TokenType::Comment => {
+                let text = token.lexeme.trim_end();
+                let rest = &trivia[i + 1..];
+                match tokens.last_mut() {
+                    Some(previous) if newlines == 0 && comments.is_empty() && ends_line(rest) => {
+                        previous.trailing.push(text);
+                    }
+                    // Punctuation stays next to the code before it, rather than the comment.
+                    Some(previous)
+                        if newlines == 0
+                            && comments.is_empty()
+                            && next_code(rest).is_some_and(|next| {
+                                matches!(
+                                    next.token_type,
+                                    TokenType::RightParen | TokenType::Comma | TokenType::Semicolon
+                                )
+                            }) =>
+                    {
+                        previous.after.push(text);
+                    }
+                    _ => comments.push(Comment {
+                        text,
+                        own_line: newlines > 0 || tokens.is_empty(),
+                        blank_line_before: newlines > 1,
+                        ends_line: rest.first().is_none_or(|next| is_newline(next)),
+                    }),
+                }
+                newlines = 0;
+            }
+            _ => {
+                tokens.push(CommentedToken {
+                    token: *token,
+                    comments: mem::take(&mut comments),
+                    after: Vec::new(),
+                    trailing: Vec::new(),
+                    blank_line_before: newlines > 1,
+                });
+                newlines = 0;
+            }
+        }
+    }
+    tokens.push(CommentedToken {
+        token: Token::new(TokenType::Eof, 0, ""),
+        comments,
+        after: Vec::new(),
+        trailing: Vec::new(),
+        blank_line_before: false,
+    });
+    tokens
+}
+
+/// Whether only comments come before the end of the line.
+fn ends_line(rest: &[Token]) -> bool {
+    let next_code = rest.iter().find(|token| match token.token_type {
+        TokenType::Comment => false,
+        TokenType::Whitespace => token.lexeme.contains('\n'),
+        _ => true,
+    });
+    next_code.is_none_or(is_newline)
+}
+
+/// The next token that isn't whitespace or a comment.
+fn next_code<'a, 'src>(rest: &'a [Token<'src>]) -> Option<&'a Token<'src>> {
+    rest.iter()
+        .find(|token| !matches!(token.token_type, TokenType::Whitespace | TokenType::Comment))
+}
+
+fn is_newline(token: &Token) -> bool {
+    token.token_type == TokenType::Whitespace && token.lexeme.contains('\n')
+}
+
+/// A description of formatted code, and of where its lines can be broken.
+#[derive(Debug)]
+enum Doc<'src> {
+    Text(&'src str),
+    /// A space, unless the line is empty or already ends with one.
+    Space,
+    /// A space, or a line break if the enclosing group doesn't fit on one line.
+    Line,
+    /// Nothing, or a line break if the enclosing group doesn't fit on one line.
+    SoftLine,
+    /// Always a line break. Several line breaks in a row only end one line.
+    HardLine,
+    /// A line break, and an empty line after it.
+    BlankLine,
+    /// Text that goes at the end of the current line, like a comment after some code.
+    /// It doesn't count towards the line's width, so moving a comment to the end of a line
+    /// never changes how the code on that line is laid out.
+    LineSuffix(&'src str),
+    Concat(Vec<Doc<'src>>),
+    /// Indents the lines started inside it.
+    Indent(Vec<Doc<'src>>),
+    /// Printed on one line if it fits, otherwise all of its `Line`s become line breaks.
+    Group(Vec<Doc<'src>>),
+}
+
+/// Parses a token stream with the compiler's grammar, turning it into a [`Doc`].
+///
+/// The source has already compiled, so this doesn't check for errors: it assumes every
+/// token is what the grammar expects.
+struct Formatter<'src> {
+    tokens: Vec<CommentedToken<'src>>,
+    current: usize,
+    /// How many interpolated expressions we're inside. They're always kept on one line.
+    flat: usize,
+}
+
+impl<'src> Formatter<'src> {
+    fn peek(&self) -> TokenType {
+        self.tokens[self.current].token.token_type
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        self.peek() == token_type
+    }
+
+    /// Consume the current token, along with its comments.
+    fn token(&mut self) -> Doc<'src> {
+        let token = &self.tokens[self.current];
+        let mut docs = Vec::new();
+        for comment in &token.comments {
+            if comment.blank_line_before {
+                docs.push(Doc::BlankLine);
+            } else if comment.own_line {
+                docs.push(Doc::HardLine);
+            } else {
+                docs.push(Doc::Space);
+            }
+            docs.push(Doc::Text(comment.text));
+            docs.push(if comment.ends_line {
+                Doc::HardLine
+            } else {
+                Doc::Space
+            });
+        }
+        if !token.comments.is_empty() && token.blank_line_before {
+            docs.push(Doc::BlankLine);
+        }
+        docs.push(Doc::Text(token.token.lexeme));
+        for &comment in &token.after {
+            docs.extend([Doc::Space, Doc::Text(comment)]);
+        }
+        docs.extend(
+            token
+                .trailing
+                .iter()
+                .map(|&comment| Doc::LineSuffix(comment)),
+        );
+        if !self.check(TokenType::Eof) {
+            self.current += 1;
+        }
+        Doc::Concat(docs)
+    }
+
+    fn line(&self) -> Doc<'src> {
+        if self.flat > 0 {
+            Doc::Text(" ")
+        } else {
+            Doc::Line
+        }
+    }
+
+    fn soft_line(&self) -> Doc<'src> {
+        if self.flat > 0 {
+            Doc::Text("")
+        } else {
+            Doc::SoftLine
+        }
+    }
+
+    /// Parse `item`s, one per line, until the end of the block or the source.
+    fn lines(&mut self, item: fn(&mut Self) -> Doc<'src>) -> Vec<Doc<'src>> {
+        // Blank lines at the start of a block are dropped.
+        let first = &mut self.tokens[self.current];
+        match first.comments.first_mut() {
+            Some(comment) => comment.blank_line_before = false,
+            None => first.blank_line_before = false,
+        }
+
+        let mut docs = Vec::new();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            if !docs.is_empty() {
+                // If the item starts with a comment, the comment keeps track of blank lines.
+                let next = &self.tokens[self.current];
+                docs.push(if next.comments.is_empty() && next.blank_line_before {
+                    Doc::BlankLine
+                } else {
+                    Doc::HardLine
+                });
+            }
+            docs.push(item(self));
+        }
+
+        // Comments before the closing brace go on lines of their own.
+        for comment in mem::take(&mut self.tokens[self.current].comments) {
+            if !docs.is_empty() {
+                docs.push(if comment.blank_line_before {
+                    Doc::BlankLine
+                } else {
+                    Doc::HardLine
+                });
+            }
+            docs.push(Doc::Text(comment.text));
+        }
+        docs
+    }
+
+    /// Braces around `item`s, one per line.
+    fn braced(&mut self, item: fn(&mut Self) -> Doc<'src>) -> Doc<'src> {
+        let open = self.token();
+        let lines = self.lines(item);
+        let close = self.token();
+        if lines.is_empty() {
+            return Doc::Concat(vec![open, close]);
+        }
+        let body = Doc::Indent(vec![Doc::HardLine, Doc::Concat(lines)]);
+        Doc::Concat(vec![open, body, Doc::HardLine, close])
+    }
+
+    /// A parenthesized, comma-separated list of `item`s, which are put on lines of
+    /// their own if they don't fit on one.
+    fn list(&mut self, item: fn(&mut Self) -> Doc<'src>) -> Doc<'src> {
+        let open = self.token();
+        if self.check(TokenType::RightParen) {
+            return Doc::Concat(vec![open, self.token()]);
+        }
+        let mut items = vec![self.soft_line()];
+        loop {
+            items.push(item(self));
+            if !self.check(TokenType::Comma) {
+                break;
+            }
+            items.push(self.token());
+            items.push(self.line());
+        }
+        let close = self.token();
+        Doc::Group(vec![open, Doc::Indent(items), self.soft_line(), close])
+    }
+
+    fn declaration(&mut self) -> Doc<'src> {
+        match self.peek() {
+            TokenType::Class => self.class_declaration(),
+            TokenType::Fun => Doc::Concat(vec![self.token(), Doc::Text(" "), self.function()]),
+            TokenType::Var => self.var_declaration(),
+            _ => self.statement(),
+        }
+    }
+
+    fn class_declaration(&mut self) -> Doc<'src> {
+        let mut docs = vec![self.token(), Doc::Text(" "), self.token()];
+        if self.check(TokenType::Less) {
+            docs.extend([Doc::Text(" "), self.token(), Doc::Text(" "), self.token()]);
+        }
+        docs.push(Doc::Text(" "));
+        docs.push(self.braced(Self::function));
+        Doc::Concat(docs)
+    }
+
+    /// A function's name, parameters and body.
+    fn function(&mut self) -> Doc<'src> {
+        let name = self.token();
+        let parameters = self.list(Self::token);
+        let body = self.braced(Self::declaration);
+        Doc::Concat(vec![name, parameters, Doc::Text(" "), body])
+    }
+
+    fn var_declaration(&mut self) -> Doc<'src> {
+        let mut docs = vec![self.token(), Doc::Text(" "), self.token()];
+        if self.check(TokenType::Equal) {
+            docs.extend([
+                Doc::Text(" "),
+                self.token(),
+                Doc::Text(" "),
+                self.expression(),
+            ]);
+        }
+        docs.push(self.token());
+        Doc::Concat(docs)
+    }
+
+    fn statement(&mut self) -> Doc<'src> {
+        match self.peek() {
+            TokenType::Print => {
+                let print = self.token();
+                let value = self.expression();
+                Doc::Concat(vec![print, Doc::Text(" "), value, self.token()])
+            }
+            TokenType::Return => {
+                let mut docs = vec![self.token()];
+                if !self.check(TokenType::Semicolon) {
+                    docs.extend([Doc::Text(" "), self.expression()]);
+                }
+                docs.push(self.token());
+                Doc::Concat(docs)
+            }
+            TokenType::If => self.if_statement(),
+            TokenType::While => {
+                let docs = vec![
+                    self.token(),
+                    Doc::Text(" "),
+                    self.token(),
+                    self.expression(),
+                    self.token(),
+                    self.body(),
+                ];
+                Doc::Concat(docs)
+            }
+            TokenType::For => self.for_statement(),
+            TokenType::LeftBrace => self.braced(Self::declaration),
+            _ => Doc::Concat(vec![self.expression(), self.token()]),
+        }
+    }
+
+    /// The body of a control flow statement. Blocks start on the same line, other
+    /// statements only if they fit.
+    fn body(&mut self) -> Doc<'src> {
+        if self.check(TokenType::LeftBrace) {
+            Doc::Concat(vec![Doc::Text(" "), self.braced(Self::declaration)])
+        } else {
+            Doc::Group(vec![Doc::Indent(vec![self.line(), self.statement()])])
+        }
+    }
+
+    fn if_statement(&mut self) -> Doc<'src> {
+        let mut docs = vec![
+            self.token(),
+            Doc::Text(" "),
+            self.token(),
+            self.expression(),
+            self.token(),
+        ];
+        let then_is_block = self.check(TokenType::LeftBrace);
+        docs.push(self.body());
+        if self.check(TokenType::Else) {
+            docs.push(if then_is_block {
+                Doc::Text(" ")
+            } else {
+                Doc::HardLine
+            });
+            docs.push(self.token());
+            if self.check(TokenType::If) {
+                docs.extend([Doc::Text(" "), self.if_statement()]);
+            } else {
+                docs.push(self.body());
+            }
+        }
+        Doc::Concat(docs)
+    }
+
+    fn for_statement(&mut self) -> Doc<'src> {
+        let mut docs = vec![self.token(), Doc::Text(" "), self.token()];
+        match self.peek() {
+            TokenType::Semicolon => docs.push(self.token()),
+            TokenType::Var => docs.push(self.var_declaration()),
+            _ => docs.extend([self.expression(), self.token()]),
+        }
+        if !self.check(TokenType::Semicolon) {
+            docs.extend([Doc::Text(" "), self.expression()]);
+        }
+        docs.push(self.token());
+        if !self.check(TokenType::RightParen) {
+            docs.extend([Doc::Text(" "), self.expression()]);
+        }
+        docs.push(self.token());
+        docs.push(self.body());
+        Doc::Concat(docs)
+    }
+
+    fn expression(&mut self) -> Doc<'src> {
+        Doc::Group(vec![self.parse_precedence(Precedence::Assignment)])
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) -> Doc<'src> {
+        let mut expression = self.prefix();
+        while precedence <= infix_precedence(self.peek()) {
+            expression = self.infix(expression);
+        }
+        if precedence <= Precedence::Assignment && self.check(TokenType::Equal) {
+            let equals = self.token();
+            let value = self.parse_precedence(Precedence::Assignment);
+            expression = Doc::Concat(vec![
+                expression,
+                Doc::Text(" "),
+                equals,
+                Doc::Text(" "),
+                value,
+            ]);
+        }
+        expression
+    }
+
+    fn prefix(&mut self) -> Doc<'src> {
+        match self.peek() {
+            TokenType::LeftParen => {
+                let docs = vec![self.token(), self.expression(), self.token()];
+                Doc::Concat(docs)
+            }
+            TokenType::Minus | TokenType::Bang => {
+                let operator = self.token();
+                Doc::Concat(vec![operator, self.parse_precedence(Precedence::Unary)])
+            }
+            TokenType::Super => Doc::Concat(vec![self.token(), self.token(), self.token()]),
+            TokenType::Interpolation => self.interpolation(),
+            // Literals, variables and `this`.
+            _ => self.token(),
+        }
+    }
+
+    fn infix(&mut self, left: Doc<'src>) -> Doc<'src> {
+        match self.peek() {
+            TokenType::LeftParen => Doc::Concat(vec![left, self.list(Self::expression)]),
+            TokenType::Dot => Doc::Concat(vec![left, self.token(), self.token()]),
+            // Binary operators, which lines are broken after.
+            operator => {
+                let precedence = infix_precedence(operator);
+                let operator = self.token();
+                let right = Doc::Group(vec![self.parse_precedence(precedence.next())]);
+                let right = Doc::Indent(vec![self.line(), right]);
+                Doc::Concat(vec![left, Doc::Text(" "), operator, right])
+            }
+        }
+    }
+
+    fn interpolation(&mut self) -> Doc<'src> {
+        let mut docs = vec![self.token()];
+        self.flat += 1;
+        loop {
+            docs.push(self.expression());
+            let more = self.check(TokenType::InterpolationMid);
+            docs.push(self.token());
+            if !more {
+                break;
+            }
+        }
+        self.flat -= 1;
+        Doc::Concat(docs)
+    }
+}
+
+/// Whether a group is printed on one line, or with all of its `Line`s broken.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+/// A doc waiting to be printed, with its indentation and its group's mode.
+type Command<'doc, 'src> = (usize, Mode, &'doc Doc<'src>);
+
+/// Lay out a doc, breaking groups' lines only where they don't fit in [`MAX_WIDTH`].
+fn print(doc: &Doc) -> String {
+    let mut printer = Printer::default();
+    let mut commands: Vec<Command> = vec![(0, Mode::Break, doc)];
+    while let Some((indent, mode, doc)) = commands.pop() {
+        match doc {
+            Doc::Text(text) => printer.text(text),
+            Doc::Space => printer.space(),
+            Doc::Line if mode == Mode::Flat => printer.text(" "),
+            Doc::SoftLine if mode == Mode::Flat => {}
+            Doc::Line | Doc::SoftLine | Doc::HardLine => printer.newline(indent),
+            Doc::BlankLine => {
+                printer.newline(indent);
+                printer.blank_line();
+            }
+            Doc::LineSuffix(text) => printer.suffixes.push(text),
+            Doc::Concat(docs) => commands.extend(docs.iter().rev().map(|doc| (indent, mode, doc))),
+            Doc::Indent(docs) => {
+                let indent = indent + INDENT;
+                commands.extend(docs.iter().rev().map(|doc| (indent, mode, doc)));
+            }
+            Doc::Group(docs) => {
+                let width = MAX_WIDTH.saturating_sub(printer.column());
+                let mode = if mode == Mode::Flat || fits(docs, &commands, width) {
+                    Mode::Flat
+                } else {
+                    Mode::Break
+                };
+                commands.extend(docs.iter().rev().map(|doc| (indent, mode, doc)));
+            }
+        }
+    }
+    printer.flush_suffixes();
+    printer.output
+}
+
+/// Whether `docs` fit in `width` columns on one line, along with whatever follows them on
+/// that line in `rest`.
+fn fits(docs: &[Doc], rest: &[Command], mut width: usize) -> bool {
+    let mut flat: Vec<_> = docs.iter().rev().map(|doc| (Mode::Flat, doc)).collect();
+    let mut rest = rest.iter().rev().map(|&(_, mode, doc)| (mode, doc));
+    while let Some((mode, doc)) = flat.pop().or_else(|| rest.next()) {
+        match doc {
+            Doc::Text(text) => {
+                let (first_line, more_lines) = match text.split_once('\n') {
+                    Some((first_line, _)) => (first_line, true),
+                    None => (*text, false),
+                };
+                let Some(left) = width.checked_sub(first_line.chars().count()) else {
+                    return false;
+                };
+                width = left;
+                if more_lines {
+                    return true;
+                }
+            }
+            Doc::Line | Doc::Space if mode == Mode::Flat => {
+                let Some(left) = width.checked_sub(1) else {
+                    return false;
+                };
+                width = left;
+            }
+            Doc::SoftLine if mode == Mode::Flat => {}
+            Doc::Line | Doc::SoftLine => return true,
+            Doc::HardLine | Doc::BlankLine => return mode == Mode::Break,
+            Doc::Space | Doc::LineSuffix(_) => {}
+            Doc::Concat(docs) | Doc::Indent(docs) | Doc::Group(docs) => {
+                flat.extend(docs.iter().rev().map(|doc| (mode, doc)));
+            }
+        }
+    }
+    true
+}
+
+#[derive(Default)]
+struct Printer<'src> {
+    output: String,
+    column: usize,
+    /// Indentation for the current line, which is written along with its first text.
+    indent: usize,
+    /// Comments waiting for the end of the line.
+    suffixes: Vec<&'src str>,
+}
+
+impl<'src> Printer<'src> {
+    fn at_line_start(&self) -> bool {
+        self.output.is_empty() || self.output.ends_with('\n')
+    }
+
+    /// The column the next text would start at.
+    fn column(&self) -> usize {
+        if self.at_line_start() {
+            self.indent
+        } else {
+            self.column
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if text.is_empty() {
+            return;
+        }
+        if self.at_line_start() {
+            self.output.extend(std::iter::repeat_n(' ', self.indent));
+            self.column = self.indent;
+        }
+        self.output.push_str(text);
+        self.column = match text.rsplit_once('\n') {
+            Some((_, last_line)) => last_line.chars().count(),
+            None => self.column + text.chars().count(),
+        };
+    }
+
+    fn space(&mut self) {
+        if !self.at_line_start() && !self.output.ends_with(' ') {
+            self.text(" ");
+        }
+    }
+
+    /// End the current line, unless it's empty, and indent the next one.
+    fn newline(&mut self, indent: usize) {
+        self.flush_suffixes();
+        if !self.at_line_start() {
+            // A space followed by a line break, like the one between `}` and an `else` that
+            // has a comment before it, would be trailing whitespace.
+            let trimmed = self.output.trim_end_matches(' ').len();
+            self.output.truncate(trimmed);
+            self.output.push('\n');
+        }
+        self.indent = indent;
+    }
+
+    /// Follow the line that just ended with an empty one.
+    fn blank_line(&mut self) {
+        if !self.output.is_empty() && !self.output.ends_with("\n\n") {
+            self.output.push('\n');
+        }
+    }
+
+    fn flush_suffixes(&mut self) {
+        for suffix in mem::take(&mut self.suffixes) {
+            self.output.push(' ');
+            self.output.push_str(suffix);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Format `source`, checking that the result means the same thing and is already formatted.
+    fn format_ok(source: &str) -> String {
+        let formatted = format(source).unwrap();
+        let tokens =
+            |source| -> Vec<_> { Scanner::new(source).map(|token| token.lexeme).collect() };
+        assert_eq!(tokens(&formatted), tokens(source));
+        assert_eq!(format(&formatted).unwrap(), formatted);
+        formatted
+    }
+
+    #[test]
+    fn formats_in_the_canonical_style() {
+        let source = "var   x=1+2*-3;class A<B{init(n){this.n=n;}get(){return super.get( ) ;}}\n\
+                      if(x>1)print x;else{print \"${ x+1 } items\";}\n\
+                      for(var i=0;i<3;i=i+1){}for(;;)x=!x;while(x and y or z)f(1,2)(3);";
+        let source = format!("class B {{}}\n{source}");
+        assert_eq!(
+            format_ok(&source),
+            "class B {}\n\
+             var x = 1 + 2 * -3;\n\
+             class A < B {\n  init(n) {\n    this.n = n;\n  }\n  get() {\n    return super.get();\n  }\n}\n\
+             if (x > 1) print x;\n\
+             else {\n  print \"${x + 1} items\";\n}\n\
+             for (var i = 0; i < 3; i = i + 1) {}\n\
+             for (;;) x = !x;\n\
+             while (x and y or z) f(1, 2)(3);\n"
+        );
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_kept() {
+        let source = "// header\n\n\n{\n\n  var a = 1; // one\n  /* two */ var b = a +\n  // three\n  2;\n\n  \
+                      // four\n}  \nif (a) {\n}\n// c\nelse {\n}\nvar\n// d\nd;\n/* five */";
+        assert_eq!(
+            format_ok(source),
+            "// header\n\n\
+             {\n  var a = 1; // one\n  /* two */ var b = a +\n    // three\n    2;\n\n  // four\n}\n\
+             if (a) {}\n// c\nelse {}\n\
+             var\n// d\nd;\n\
+             /* five */\n"
+        );
+    }
+
+    #[test]
+    fn comments_anywhere_are_formatted_stably() {
+        let programs = [
+            "if (a) print a; else { print b; }",
+            "fun f(a, b) { return f(a, b).g; }",
+            "class A < B { m() { super.m(-1 + 2 * 3); } }",
+            "for (var i = 0; i < 3; i = i + 1) while (!i) i = i or nil;",
+            "var s = \"a ${b + 1} c\"; print s;",
+        ];
+        for program in programs {
+            let boundaries =
+                Scanner::new(program).flat_map(|token| [token.start, token.start + token.length]);
+            for at in boundaries {
+                for comment in ["// c\n", "/* c */"] {
+                    let source = format!("{}{comment}{}", &program[..at], &program[at..]);
+                    let formatted = format_ok(&source);
+                    let badly_spaced = formatted.lines().any(|line| {
+                        line.ends_with(' ')
+                            || [" ;", " ,", " )", "a/*"]
+                                .iter()
+                                .any(|bad| line.contains(bad))
+                    });
+                    assert!(!badly_spaced, "{source:?} formatted to {formatted:?}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn long_lines_are_wrapped() {
+        let source = "fun f(first_parameter, second_parameter, third_parameter, fourth_parameter_name) {\n\
+                      print first_parameter + second_parameter * third_parameter - fourth_parameter_name;}";
+        assert_eq!(
+            format_ok(source),
+            "fun f(\n  first_parameter,\n  second_parameter,\n  third_parameter,\n  fourth_parameter_name\n) {\n  \
+             print first_parameter +\n    second_parameter * third_parameter -\n    fourth_parameter_name;\n}\n"
+        );
+    }
+
+    #[test]
+    fn only_code_that_compiles_is_formatted() {
+        assert!(matches!(format("print (1;"), Err(Error::Compile(_))));
+        assert_eq!(format_ok(""), "");
+    }
+}
@@ -0,0 +1,374 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{
+    object::{
+        ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjNative, ObjRef,
+        ObjString, ObjUpvalue, Object,
+    },
+    value::Value,
+};
+
+/// Don't bother collecting until the heap has at least this many bytes.
+const MIN_NEXT_GC: usize = 1024 * 1024;
+/// After a collection, let the heap grow to this multiple of its live size before the next one.
+const HEAP_GROW_FACTOR: usize = 2;
+
+/// Owns every object the VM allocates. Values refer to objects through [`ObjRef`] handles.
+///
+/// Memory is reclaimed by a mark-sweep collector. The heap can't see its roots, so its owner
+/// decides when to collect: check [`Heap::should_collect`] before allocating, mark every
+/// root with [`Heap::mark_value`] and [`Heap::mark_object`], then call [`Heap::collect`].
+#[derive(Debug)]
+pub struct Heap {
+    /// Freed slots are None, and get reused by later allocations.
+    objects: Vec<Option<Slot>>,
+    free_slots: Vec<usize>,
+    marker: Marker,
+    /// Intern table: every string object on the heap, keyed by its contents.
+    /// It doesn't keep strings alive; unreachable strings are removed from it during collection.
+    strings: HashMap<Rc<str>, ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+}
+
+#[derive(Debug)]
+struct Slot {
+    object: Object,
+    /// How many bytes this object was counted as when it was allocated.
+    size: usize,
+}
+
+/// The mark phase's state, kept apart from the objects so that objects can be traced
+/// while they're borrowed.
+#[derive(Debug, Default)]
+struct Marker {
+    /// Parallel to `Heap::objects`.
+    marks: Vec<bool>,
+    /// Objects that have been marked, but whose references haven't been marked yet.
+    gray: Vec<ObjRef>,
+}
+
+impl Marker {
+    fn mark_object(&mut self, obj: ObjRef) {
+        if !self.marks[obj.0] {
+            self.marks[obj.0] = true;
+            self.gray.push(obj);
+        }
+    }
+
+    fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(obj) = value {
+            self.mark_object(obj);
+        }
+    }
+
+    /// Mark every object that `object` refers to.
+    fn mark_references(&mut self, object: &Object) {
+        match object {
+            Object::String(_) => {}
+            Object::Function(function) => {
+                if let Some(name) = function.name {
+                    self.mark_object(name);
+                }
+                for &constant in &function.chunk.constants {
+                    self.mark_value(constant);
+                }
+            }
+            Object::Closure(closure) => {
+                self.mark_object(closure.function);
+                for &upvalue in &closure.upvalues {
+                    self.mark_object(upvalue);
+                }
+            }
+            // An open upvalue's variable is on the stack, which is already a root.
+            Object::Upvalue(ObjUpvalue::Open(_)) => {}
+            Object::Upvalue(ObjUpvalue::Closed(value)) => self.mark_value(*value),
+            Object::Class(class) => {
+                self.mark_object(class.name);
+                for (&name, &method) in &class.methods {
+                    self.mark_object(name);
+                    self.mark_value(method);
+                }
+            }
+            Object::Instance(instance) => {
+                self.mark_object(instance.class);
+                for (&name, &value) in &instance.fields {
+                    self.mark_object(name);
+                    self.mark_value(value);
+                }
+            }
+            Object::BoundMethod(bound) => {
+                self.mark_value(bound.receiver);
+                self.mark_object(bound.method);
+            }
+            Object::Native(native) => self.mark_object(native.name),
+        }
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            objects: Vec::new(),
+            free_slots: Vec::new(),
+            marker: Marker::default(),
+            strings: HashMap::new(),
+            bytes_allocated: 0,
+            next_gc: MIN_NEXT_GC,
+        }
+    }
+}
+
+impl Heap {
+    /// Store a new object on the heap. This never collects garbage, see [`Heap::should_collect`].
+    pub fn alloc(&mut self, object: Object) -> ObjRef {
+        let size = object.size();
+        self.bytes_allocated += size;
+        let slot = Some(Slot { object, size });
+        if let Some(i) = self.free_slots.pop() {
+            self.objects[i] = slot;
+            ObjRef(i)
+        } else {
+            self.objects.push(slot);
+            self.marker.marks.push(false);
+            ObjRef(self.objects.len() - 1)
+        }
+    }
+
+    /// Update the size counted for an object that has grown since it was allocated, like an
+    /// instance that got new fields, so that [`Heap::should_collect`] knows about the memory.
+    pub fn remeasure(&mut self, obj: ObjRef) {
+        let Some(slot) = &mut self.objects[obj.0] else {
+            panic!("use of freed object {obj:?}");
+        };
+        let size = slot.object.size();
+        self.bytes_allocated = self.bytes_allocated - slot.size + size;
+        slot.size = size;
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Object {
+        match &self.objects[obj.0] {
+            Some(slot) => &slot.object,
+            None => panic!("use of freed object {obj:?}"),
+        }
+    }
+
+    /// Get the contents of a string object, or None if `obj` is some other kind of object.
+    pub fn as_str(&self, obj: ObjRef) -> Option<&str> {
+        match self.get(obj) {
+            Object::String(s) => Some(&s.chars),
+            _ => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Object {
+        match &mut self.objects[obj.0] {
+            Some(slot) => &mut slot.object,
+            None => panic!("use of freed object {obj:?}"),
+        }
+    }
+
+    pub fn as_closure(&self, obj: ObjRef) -> Option<&ObjClosure> {
+        match self.get(obj) {
+            Object::Closure(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    pub fn as_upvalue(&self, obj: ObjRef) -> Option<&ObjUpvalue> {
+        match self.get(obj) {
+            Object::Upvalue(u) => Some(u),
+            _ => None,
+        }
+    }
+
+    pub fn as_function(&self, obj: ObjRef) -> Option<&ObjFunction> {
+        match self.get(obj) {
+            Object::Function(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    pub fn as_class(&self, obj: ObjRef) -> Option<&ObjClass> {
+        match self.get(obj) {
+            Object::Class(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self, obj: ObjRef) -> Option<&ObjInstance> {
+        match self.get(obj) {
+            Object::Instance(i) => Some(i),
+            _ => None,
+        }
+    }
+
+    pub fn as_bound_method(&self, obj: ObjRef) -> Option<&ObjBoundMethod> {
+        match self.get(obj) {
+            Object::BoundMethod(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_native(&self, obj: ObjRef) -> Option<&ObjNative> {
+        match self.get(obj) {
+            Object::Native(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    /// Find or create the string object with these contents.
+    pub fn intern(&mut self, chars: &str) -> ObjRef {
+        if let Some(&obj) = self.strings.get(chars) {
+            return obj;
+        }
+        self.intern_new(Rc::from(chars))
+    }
+
+    /// Like [`Heap::intern`], but takes ownership of an already-built string.
+    pub fn intern_owned(&mut self, chars: String) -> ObjRef {
+        if let Some(&obj) = self.strings.get(chars.as_str()) {
+            return obj;
+        }
+        self.intern_new(Rc::from(chars))
+    }
+
+    fn intern_new(&mut self, chars: Rc<str>) -> ObjRef {
+        let obj = self.alloc(Object::String(ObjString {
+            chars: Rc::clone(&chars),
+        }));
+        self.strings.insert(chars, obj);
+        obj
+    }
+
+    #[cfg(test)]
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    /// Has the heap grown enough since the last collection that it's worth collecting again?
+    /// With the `gc-stress` feature, this is always true.
+    pub fn should_collect(&self) -> bool {
+        cfg!(feature = "gc-stress") || self.bytes_allocated > self.next_gc
+    }
+
+    /// Mark an object as reachable, so the next [`Heap::collect`] keeps it.
+    pub fn mark_object(&mut self, obj: ObjRef) {
+        self.marker.mark_object(obj);
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        self.marker.mark_value(value);
+    }
+
+    /// Mark everything an object refers to. Use this to keep an object's references alive
+    /// while it's being built, before it has been allocated on the heap.
+    pub fn mark_references(&mut self, object: &Object) {
+        self.marker.mark_references(object);
+    }
+
+    /// Free every object that isn't reachable from the objects marked since the last collection.
+    pub fn collect(&mut self) {
+        let before = self.bytes_allocated;
+        if cfg!(feature = "trace") {
+            println!("-- gc begin");
+        }
+
+        // Trace: mark everything reachable from the roots.
+        while let Some(obj) = self.marker.gray.pop() {
+            let Some(slot) = &self.objects[obj.0] else {
+                panic!("marked freed object {obj:?}");
+            };
+            self.marker.mark_references(&slot.object);
+        }
+
+        // The intern table holds weak references, so drop strings that are about to be freed.
+        let marks = &self.marker.marks;
+        self.strings.retain(|_, obj| marks[obj.0]);
+
+        // Sweep: free everything that wasn't marked, and clear marks for the next collection.
+        for (i, slot) in self.objects.iter_mut().enumerate() {
+            let marked = std::mem::replace(&mut self.marker.marks[i], false);
+            if marked {
+                continue;
+            }
+            if let Some(freed) = slot.take() {
+                self.bytes_allocated -= freed.size;
+                self.free_slots.push(i);
+            }
+        }
+
+        self.next_gc = (self.bytes_allocated * HEAP_GROW_FACTOR).max(MIN_NEXT_GC);
+        if cfg!(feature = "trace") {
+            println!(
+                "-- gc end: collected {} bytes (from {before} to {}), next at {}",
+                before - self.bytes_allocated,
+                self.bytes_allocated,
+                self.next_gc
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interning_dedupes_strings() {
+        let mut heap = Heap::default();
+        let a = heap.intern("lox");
+        let b = heap.intern_owned(String::from("lox"));
+        let c = heap.intern("clox");
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_eq!(heap.objects.len(), 2);
+    }
+
+    #[test]
+    fn collect_frees_unmarked_objects() {
+        let mut heap = Heap::default();
+        let kept = heap.intern("kept");
+        let name = heap.intern("f");
+        let mut function = ObjFunction::new(Some(name));
+        function.chunk.constants.push(Value::Obj(kept));
+        let function = heap.alloc(Object::Function(function));
+        heap.intern("garbage");
+
+        heap.mark_object(function);
+        heap.collect();
+
+        assert_eq!(heap.as_str(kept), Some("kept"));
+        assert_eq!(heap.as_str(name), Some("f"));
+        assert!(heap.objects.iter().filter(|slot| slot.is_none()).count() == 1);
+        // The freed string is gone from the intern table, and its slot gets reused.
+        let fresh = heap.intern("garbage");
+        assert_eq!(heap.as_str(fresh), Some("garbage"));
+        assert!(heap.free_slots.is_empty());
+    }
+
+    #[test]
+    fn growing_objects_are_counted() {
+        let mut heap = Heap::default();
+        let class = heap.intern("A");
+        let class = heap.alloc(Object::Class(ObjClass::new(class)));
+        let instance = heap.alloc(Object::Instance(ObjInstance::new(class)));
+        let names: Vec<_> = (0..16).map(|i| heap.intern(&format!("field{i}"))).collect();
+        let before = heap.bytes_allocated();
+        for name in names {
+            if let Object::Instance(object) = heap.get_mut(instance) {
+                object.fields.insert(name, Value::Nil);
+            }
+            heap.remeasure(instance);
+        }
+        let grown = heap.bytes_allocated() - before;
+        assert!(
+            grown >= 16 * size_of::<(ObjRef, Value)>(),
+            "grew by {grown}"
+        );
+
+        // Freeing the instance takes back everything counted for it.
+        heap.collect();
+        assert_eq!(heap.bytes_allocated(), 0);
+    }
+}
@@ -0,0 +1,23 @@
+//! A bytecode virtual machine for the Lox programming language.
+//!
+//! Host programs create a [`Vm`], register any native functions they want scripts to be able
+//! to call with [`Vm::define_native`], then run Lox source code with [`Vm::interpret`].
+//!
+//! Tools that work on source code, rather than running it, can split it into tokens with a
+//! [`Scanner`], or lay it out in the canonical style with [`format()`].
+
+mod chunk;
+mod compiler;
+mod formatter;
+mod heap;
+mod object;
+mod opcode;
+mod tokenizer;
+mod value;
+mod vm;
+
+pub use formatter::format;
+pub use object::{NativeError, ObjRef};
+pub use tokenizer::{Scanner, Span, Token, TokenType};
+pub use value::Value;
+pub use vm::{CompileErr, Error, RuntimeErr, TraceFrame, Vm};
@@ -1,21 +1,14 @@
-mod chunk;
-mod compiler;
-mod opcode;
-mod tokenizer;
-mod value;
-mod vm;
-
 use std::process::exit;
 
-use vm::Vm;
+use lox_vm::Vm;
 
 fn main() {
     let mut args = std::env::args();
     let _ = args.next();
-    let res = if let Some(filepath) = args.next() {
-        run_file(filepath)
-    } else {
-        repl()
+    let res = match args.next() {
+        Some(command) if command == "fmt" => format_files(args.collect()),
+        Some(filepath) => run_file(filepath),
+        None => repl(),
     };
     match res {
         Ok(()) => {}
@@ -23,41 +16,93 @@ fn main() {
             eprintln!("{err}");
             exit(1);
         }
-        Err(Error::Vm(vm::Error::Runtime(err))) => {
-            eprintln!("{err}");
-            exit(2);
-        }
-        Err(Error::Vm(vm::Error::Compile(err))) => {
-            eprintln!("{err}");
-            exit(3);
-        }
+        // VM errors have already been reported, alongside the source code they came from.
+        Err(Error::Vm(lox_vm::Error::Runtime { .. })) => exit(2),
+        Err(Error::Vm(lox_vm::Error::Compile(_))) => exit(3),
+        // So have the files that need formatting.
+        Err(Error::Unformatted) => exit(4),
     }
 }
 
 #[derive(Debug, thiserror::Error)]
 enum Error {
     #[error("The lox VM returned an error: {0}")]
-    Vm(#[from] vm::Error),
+    Vm(#[from] lox_vm::Error),
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("Some files are not formatted")]
+    Unformatted,
 }
 
 fn repl() -> Result<(), Error> {
-    let mut lines = std::io::stdin().lines();
+    let lines = std::io::stdin().lines();
     let mut vm = Vm::new();
     print!("> ");
-    while let Some(line) = lines.next() {
-        vm.interpret(&line?)?;
+    for line in lines {
+        let line = line?;
+        // The VM resets itself after an error, so carry on with the next line.
+        if let Err(err) = vm.interpret(&line) {
+            report(&err, &line);
+        }
         print!("> ");
     }
     Ok(())
 }
 
 fn run_file(filepath: String) -> Result<(), Error> {
-    let f = std::fs::read_to_string(filepath)?;
+    let source = std::fs::read_to_string(filepath)?;
     let mut vm = Vm::new();
-    for line in f.lines() {
-        vm.interpret(&line)?;
-    }
+    vm.interpret(&source)
+        .inspect_err(|err| report(err, &source))?;
     Ok(())
 }
+
+/// `lox fmt [--check] [FILE]...`: format files in place, or with `--check`, list the ones
+/// that aren't formatted. Without any files, formats standard input to standard output.
+fn format_files(args: Vec<String>) -> Result<(), Error> {
+    let check = args.iter().any(|arg| arg == "--check");
+    let paths: Vec<_> = args.iter().filter(|arg| *arg != "--check").collect();
+    if paths.is_empty() {
+        let source = std::io::read_to_string(std::io::stdin())?;
+        let formatted = lox_vm::format(&source).inspect_err(|err| report(err, &source))?;
+        if !check {
+            print!("{formatted}");
+        } else if formatted != source {
+            eprintln!("<stdin> is not formatted");
+            return Err(Error::Unformatted);
+        }
+        return Ok(());
+    }
+
+    let mut unformatted = false;
+    for path in paths {
+        let source = std::fs::read_to_string(path)?;
+        let formatted = lox_vm::format(&source).inspect_err(|err| report(err, &source))?;
+        if formatted == source {
+            continue;
+        }
+        if check {
+            eprintln!("{path} is not formatted");
+            unformatted = true;
+        } else {
+            std::fs::write(path, formatted)?;
+        }
+    }
+    if unformatted {
+        Err(Error::Unformatted)
+    } else {
+        Ok(())
+    }
+}
+
+/// Print an error from running `source`.
+fn report(err: &lox_vm::Error, source: &str) {
+    match err {
+        lox_vm::Error::Compile(errors) => {
+            for err in errors {
+                eprintln!("{}\n", err.render(source));
+            }
+        }
+        lox_vm::Error::Runtime { .. } => eprintln!("{err}"),
+    }
+}
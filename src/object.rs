@@ -0,0 +1,155 @@
+use std::{collections::HashMap, fmt, mem::size_of, rc::Rc};
+
+use crate::{
+    chunk::{Chunk, LineRun},
+    value::Value,
+    vm::Vm,
+};
+
+/// A handle to an object allocated on the [`Heap`](crate::heap::Heap).
+/// Two handles are equal exactly when they refer to the same object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(pub(crate) usize);
+
+#[derive(Debug)]
+pub enum Object {
+    String(ObjString),
+    Function(ObjFunction),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+    Native(ObjNative),
+}
+
+impl Object {
+    /// Roughly how many bytes this object owns, for deciding when to collect garbage.
+    pub fn size(&self) -> usize {
+        let owned = match self {
+            Self::String(s) => s.chars.len(),
+            Self::Function(f) => {
+                f.chunk.code.capacity()
+                    + f.chunk.constants.capacity() * size_of::<Value>()
+                    + f.chunk.lines.capacity() * size_of::<LineRun>()
+            }
+            Self::Closure(c) => c.upvalues.capacity() * size_of::<ObjRef>(),
+            Self::Upvalue(_) => 0,
+            Self::Class(c) => c.methods.capacity() * size_of::<(ObjRef, Value)>(),
+            Self::Instance(i) => i.fields.capacity() * size_of::<(ObjRef, Value)>(),
+            Self::BoundMethod(_) | Self::Native(_) => 0,
+        };
+        size_of::<Self>() + owned
+    }
+}
+
+/// An immutable, interned string.
+#[derive(Debug)]
+pub struct ObjString {
+    /// Shared with the heap's intern table, so each distinct string is stored once.
+    pub chars: Rc<str>,
+}
+
+/// A compiled Lox function.
+#[derive(Debug)]
+pub struct ObjFunction {
+    pub arity: usize,
+    /// How many variables from enclosing functions this function captures.
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    /// None for the top-level script.
+    pub name: Option<ObjRef>,
+}
+
+impl ObjFunction {
+    pub fn new(name: Option<ObjRef>) -> Self {
+        Self {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::default(),
+            name,
+        }
+    }
+}
+
+/// A function together with the variables it captured from enclosing scopes.
+/// All Lox functions are wrapped in a closure at runtime.
+#[derive(Debug)]
+pub struct ObjClosure {
+    pub function: ObjRef,
+    /// Points to an [`ObjUpvalue`] for each variable the function captures.
+    pub upvalues: Vec<ObjRef>,
+}
+
+/// A captured variable.
+#[derive(Debug)]
+pub enum ObjUpvalue {
+    /// The variable is still alive on the VM stack, at this index.
+    Open(usize),
+    /// The variable went out of scope, so the upvalue now owns its value.
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct ObjClass {
+    pub name: ObjRef,
+    /// Closures, keyed by their interned method name.
+    pub methods: HashMap<ObjRef, Value>,
+}
+
+impl ObjClass {
+    pub fn new(name: ObjRef) -> Self {
+        Self {
+            name,
+            methods: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjInstance {
+    pub class: ObjRef,
+    /// Fields are created by assigning to them, so any instance can have any fields.
+    pub fields: HashMap<ObjRef, Value>,
+}
+
+impl ObjInstance {
+    pub fn new(class: ObjRef) -> Self {
+        Self {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+/// A method closure that remembers which instance it was accessed from, so `this` works
+/// when it's called later.
+#[derive(Debug)]
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+/// The signature of a Rust function that Lox code can call. It gets the VM, so it can
+/// allocate objects, and the call's arguments. Errors become a [`RuntimeErr`](crate::RuntimeErr).
+pub type NativeFn = dyn Fn(&mut Vm, &[Value]) -> Result<Value, NativeError>;
+
+/// Any error a native function wants to raise. If it's a [`RuntimeErr`](crate::RuntimeErr),
+/// it's reported as-is, otherwise it's wrapped in [`RuntimeErr::Native`](crate::RuntimeErr::Native).
+pub type NativeError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A function implemented in Rust by the host program.
+pub struct ObjNative {
+    pub name: ObjRef,
+    pub arity: usize,
+    pub function: Rc<NativeFn>,
+}
+
+impl fmt::Debug for ObjNative {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ObjNative")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish_non_exhaustive()
+    }
+}
@@ -8,6 +8,42 @@ pub enum Opcode {
     Sub,
     Mul,
     Div,
+    Nil,
+    True,
+    False,
+    Not,
+    Equal,
+    Greater,
+    Less,
+    Print,
+    Pop,
+    DefineGlobal,
+    GetGlobal,
+    SetGlobal,
+    GetLocal,
+    SetLocal,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Closure,
+    GetUpvalue,
+    SetUpvalue,
+    CloseUpvalue,
+    Class,
+    GetProperty,
+    SetProperty,
+    Method,
+    Inherit,
+    GetSuper,
+    /// Like `Constant`, but with a three-byte operand, for chunks with over 256 constants.
+    ConstantLong,
+    /// Replace the value on top of the stack with its string representation, for
+    /// interpolating it into a string.
+    ToString,
+    /// A prefix giving the next instruction's constant operand three bytes, like
+    /// `ConstantLong`'s. Used for names and functions past the first 256 constants.
+    Wide,
 }
 
 impl From<Opcode> for u8 {
@@ -28,6 +64,37 @@ impl TryFrom<u8> for Opcode {
             4 => Self::Sub,
             5 => Self::Mul,
             6 => Self::Div,
+            7 => Self::Nil,
+            8 => Self::True,
+            9 => Self::False,
+            10 => Self::Not,
+            11 => Self::Equal,
+            12 => Self::Greater,
+            13 => Self::Less,
+            14 => Self::Print,
+            15 => Self::Pop,
+            16 => Self::DefineGlobal,
+            17 => Self::GetGlobal,
+            18 => Self::SetGlobal,
+            19 => Self::GetLocal,
+            20 => Self::SetLocal,
+            21 => Self::Jump,
+            22 => Self::JumpIfFalse,
+            23 => Self::Loop,
+            24 => Self::Call,
+            25 => Self::Closure,
+            26 => Self::GetUpvalue,
+            27 => Self::SetUpvalue,
+            28 => Self::CloseUpvalue,
+            29 => Self::Class,
+            30 => Self::GetProperty,
+            31 => Self::SetProperty,
+            32 => Self::Method,
+            33 => Self::Inherit,
+            34 => Self::GetSuper,
+            35 => Self::ConstantLong,
+            36 => Self::ToString,
+            37 => Self::Wide,
             _ => return Err(CouldNotDecodeOpcode { opcode }),
         };
         Ok(opcode)
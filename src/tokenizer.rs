@@ -1,31 +1,72 @@
 // Code taken from https://raw.githubusercontent.com/victorcwai/rust-lox/main/src/scanner.rs
 
+use std::iter::FusedIterator;
+
+/// Splits Lox source code into [`Token`]s, either by calling [`Scanner::scan_token`] until it
+/// returns [`TokenType::Eof`], or by iterating over it.
+///
+/// By default whitespace and comments are skipped. Scanners made with [`Scanner::with_trivia`]
+/// return them as tokens too, so that every byte of the source is in some token's lexeme.
 pub struct Scanner<'src> {
     start: usize, // beginning of the current lexeme being scanned
     current: usize,
     src: &'src str,
-    pub line: usize,
+    line: usize,
+    /// Column of the next character, counted in characters from 1. It's kept up to date as
+    /// characters are consumed, so that long lines don't have to be counted for each token.
+    column: usize,
+    /// Line and column of the current lexeme's first character.
+    start_line: usize,
+    start_column: usize,
+    /// One entry per string interpolation we're inside, counting the braces opened in its
+    /// expression that haven't been closed yet. The `}` that closes the interpolation itself
+    /// resumes scanning the string.
+    interpolations: Vec<usize>,
+    /// Whether to return whitespace and comments, rather than skipping them.
+    trivia: bool,
 }
 impl<'src> Scanner<'src> {
-    pub fn new(source: &str) -> Scanner {
+    pub fn new(source: &'src str) -> Scanner<'src> {
         Scanner {
             start: 0,
             current: 0,
             src: source,
             line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
+            interpolations: Vec::new(),
+            trivia: false,
+        }
+    }
+
+    /// A scanner that also returns [`TokenType::Whitespace`] and [`TokenType::Comment`]
+    /// tokens, so that concatenating the lexemes of all its tokens gives back `source`.
+    pub fn with_trivia(source: &'src str) -> Scanner<'src> {
+        Scanner {
+            trivia: true,
+            ..Scanner::new(source)
         }
     }
 
     pub fn scan_token(&mut self) -> Token<'src> {
-        self.skip_whitespace();
-        self.start = self.current;
+        loop {
+            self.begin_token();
+            match self.trivia() {
+                Some(token) if self.trivia || matches!(token.token_type, TokenType::Error(_)) => {
+                    return token;
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
         }
 
         let c = self.advance();
-        if is_alpha(c) {
+        if is_identifier_start(c) {
             return self.identifier();
         };
         if is_digit(c) {
@@ -33,62 +74,82 @@ impl<'src> Scanner<'src> {
         };
 
         match c {
-            // note: compare c (u8) with ASCII letters (e.g. b'*')
-            b'(' => self.make_token(TokenType::LeftParen),
-            b')' => self.make_token(TokenType::RightParen),
-            b'{' => self.make_token(TokenType::LeftBrace),
-            b'}' => self.make_token(TokenType::RightBrace),
-            b';' => self.make_token(TokenType::Semicolon),
-            b',' => self.make_token(TokenType::Comma),
-            b'.' => self.make_token(TokenType::Dot),
-            b'-' => self.make_token(TokenType::Minus),
-            b'+' => self.make_token(TokenType::Plus),
-            b'/' => self.make_token(TokenType::Slash),
-            b'*' => self.make_token(TokenType::Star),
-            b'!' if self.check_next(b'=') => self.make_token(TokenType::BangEqual),
-            b'!' => self.make_token(TokenType::Bang),
-            b'=' if self.check_next(b'=') => self.make_token(TokenType::EqualEqual),
-            b'=' => self.make_token(TokenType::Equal),
-            b'<' if self.check_next(b'=') => self.make_token(TokenType::LessEqual),
-            b'<' => self.make_token(TokenType::Less),
-            b'>' if self.check_next(b'=') => self.make_token(TokenType::GreaterEqual),
-            b'>' => self.make_token(TokenType::Greater),
-            b'"' => self.string(),
+            '(' => self.make_token(TokenType::LeftParen),
+            ')' => self.make_token(TokenType::RightParen),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.make_token(TokenType::LeftBrace)
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string(true)
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    self.make_token(TokenType::RightBrace)
+                }
+                None => self.make_token(TokenType::RightBrace),
+            },
+            ';' => self.make_token(TokenType::Semicolon),
+            ',' => self.make_token(TokenType::Comma),
+            '.' => self.make_token(TokenType::Dot),
+            '-' => self.make_token(TokenType::Minus),
+            '+' => self.make_token(TokenType::Plus),
+            '/' => self.make_token(TokenType::Slash),
+            '*' => self.make_token(TokenType::Star),
+            '!' if self.check_next('=') => self.make_token(TokenType::BangEqual),
+            '!' => self.make_token(TokenType::Bang),
+            '=' if self.check_next('=') => self.make_token(TokenType::EqualEqual),
+            '=' => self.make_token(TokenType::Equal),
+            '<' if self.check_next('=') => self.make_token(TokenType::LessEqual),
+            '<' => self.make_token(TokenType::Less),
+            '>' if self.check_next('=') => self.make_token(TokenType::GreaterEqual),
+            '>' => self.make_token(TokenType::Greater),
+            '"' if self.peek() == '"' && self.peek_next() == '"' => self.raw_string(),
+            '"' => self.string(false),
             _ => self.error_token("Unexpected character."),
         }
     }
 
+    /// Start a new lexeme at the current position.
+    fn begin_token(&mut self) {
+        self.start = self.current;
+        self.start_line = self.line;
+        self.start_column = self.column;
+    }
+
     fn is_at_end(&self) -> bool {
         self.current == self.src.len()
     }
 
-    fn advance(&mut self) -> u8 {
-        let c = self.src.as_bytes()[self.current];
-        self.current += 1;
+    /// Consume the next character. Returns '\0' at the end of the source.
+    fn advance(&mut self) -> char {
+        let c = self.peek();
+        if !self.is_at_end() {
+            self.current += c.len_utf8();
+            self.column += 1;
+        }
         c
     }
 
-    fn peek(&self) -> u8 {
-        if self.is_at_end() {
-            b'\0'
-        } else {
-            self.src.as_bytes()[self.current]
-        }
+    /// The next character, or '\0' at the end of the source.
+    fn peek(&self) -> char {
+        self.src[self.current..].chars().next().unwrap_or('\0')
     }
 
-    fn peek_next(&self) -> u8 {
-        if self.current > self.src.len() - 2 {
-            b'\0'
-        } else {
-            self.src.as_bytes()[self.current + 1]
-        }
+    /// The character after the next one, or '\0' if there isn't one.
+    fn peek_next(&self) -> char {
+        self.src[self.current..].chars().nth(1).unwrap_or('\0')
     }
 
-    fn check_next(&mut self, expected: u8) -> bool {
-        if self.is_at_end() || self.src.as_bytes()[self.current] != expected {
+    fn check_next(&mut self, expected: char) -> bool {
+        if self.is_at_end() || self.peek() != expected {
             false
         } else {
-            self.current += 1;
+            self.advance();
             true
         }
     }
@@ -96,49 +157,76 @@ impl<'src> Scanner<'src> {
     fn make_token(&self, token_type: TokenType) -> Token<'src> {
         Token {
             token_type,
-            // start: self.start,
-            // length: self.current - self.start,
-            line: self.line,
+            start: self.start,
+            length: self.current - self.start,
+            line: self.start_line,
+            column: self.start_column,
             lexeme: &self.src[self.start..self.current],
         }
     }
 
-    fn error_token(&self, msg: &'src str) -> Token<'src> {
-        // 'static indicates that the data pointed to by the reference lives for the entire
-        // lifetime of the running program. It can still be coerced to a shorter lifetime.
-        Token {
-            token_type: TokenType::Error,
-            // token.start = message;
-            // token.length = (int)strlen(message);
-            line: self.line,
-            lexeme: msg,
-        }
+    /// An invalid token. Its lexeme is still the source text, the message goes in its type.
+    fn error_token(&self, msg: &'static str) -> Token<'src> {
+        self.make_token(TokenType::Error(msg))
     }
 
-    fn skip_whitespace(&mut self) {
-        loop {
-            match self.peek() {
-                b' ' | b'\r' | b'\t' => {
-                    self.advance();
-                }
-                b'\n' => {
-                    self.line += 1;
-                    self.advance();
-                }
-                b'/' => {
-                    if self.peek_next() == b'/' {
-                        // A comment goes until the end of the line.
-                        while self.peek() != b'\n' && !self.is_at_end() {
+    /// Consume a newline, keeping track of where the next line starts.
+    fn newline(&mut self) {
+        self.advance();
+        self.line += 1;
+        self.column = 1;
+    }
+
+    /// Scan a run of whitespace or a comment, if that's what comes next.
+    fn trivia(&mut self) -> Option<Token<'src>> {
+        match self.peek() {
+            ' ' | '\r' | '\t' | '\n' => {
+                loop {
+                    match self.peek() {
+                        ' ' | '\r' | '\t' => {
                             self.advance();
                         }
-                    } else {
-                        return;
+                        '\n' => self.newline(),
+                        _ => break,
                     }
-                    break;
                 }
-                _ => return,
+                Some(self.make_token(TokenType::Whitespace))
+            }
+            '/' if self.peek_next() == '/' => {
+                // A comment goes until the end of the line.
+                while self.peek() != '\n' && !self.is_at_end() {
+                    self.advance();
+                }
+                Some(self.make_token(TokenType::Comment))
+            }
+            '/' if self.peek_next() == '*' => Some(self.block_comment()),
+            _ => None,
+        }
+    }
+
+    /// Scan a `/* ... */` comment. These can be nested, so commenting out code that already
+    /// contains block comments works.
+    fn block_comment(&mut self) -> Token<'src> {
+        self.advance();
+        self.advance();
+        let mut depth = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                return self.error_token("Unterminated block comment.");
+            }
+            if self.peek() == '/' && self.peek_next() == '*' {
+                self.advance();
+                self.advance();
+                depth += 1;
+            } else if self.peek() == '*' && self.peek_next() == '/' {
+                self.advance();
+                self.advance();
+                depth -= 1;
+            } else {
+                self.advance_in_literal();
             }
         }
+        self.make_token(TokenType::Comment)
     }
 
     fn check_keyword(
@@ -191,37 +279,124 @@ impl<'src> Scanner<'src> {
     }
 
     fn identifier(&mut self) -> Token<'src> {
-        while is_alpha(self.peek()) || is_digit(self.peek()) {
+        while is_identifier_continue(self.peek()) {
             self.advance();
         }
         self.make_token(self.identifier_type())
     }
 
     fn number(&mut self) -> Token<'src> {
-        while is_digit(self.peek()) {
-            self.advance();
+        match self.number_literal() {
+            Ok(()) => self.make_token(TokenType::Number),
+            Err(msg) => {
+                // Make the error cover the whole malformed literal, e.g. all of `0b123`.
+                while is_identifier_continue(self.peek()) {
+                    self.advance();
+                }
+                self.error_token(msg)
+            }
         }
+    }
 
-        // Look for a fractional part.
-        if self.peek() == b'.' && is_digit(self.peek_next()) {
-            // Consume the ".".
+    /// Scan the rest of a number whose first digit has been consumed. Numbers are decimal,
+    /// optionally with a fraction and exponent, or integers with a `0x`, `0b` or `0o` prefix.
+    fn number_literal(&mut self) -> Result<(), &'static str> {
+        let first = self.src.as_bytes()[self.start];
+        let radix = match self.peek() {
+            'x' | 'X' if first == b'0' => Some((16, "Expect hex digits after '0x'.")),
+            'b' | 'B' if first == b'0' => Some((2, "Expect binary digits after '0b'.")),
+            'o' | 'O' if first == b'0' => Some((8, "Expect octal digits after '0o'.")),
+            _ => None,
+        };
+        if let Some((radix, missing_digits)) = radix {
             self.advance();
+            if self.digits(radix, false)? == 0 {
+                return Err(missing_digits);
+            }
+        } else {
+            self.digits(10, true)?;
+
+            // Look for a fractional part.
+            if self.peek() == '.' && is_digit(self.peek_next()) {
+                // Consume the ".".
+                self.advance();
+                self.digits(10, false)?;
+            }
 
-            while is_digit(self.peek()) {
+            // Look for an exponent.
+            if matches!(self.peek(), 'e' | 'E') {
                 self.advance();
+                if matches!(self.peek(), '+' | '-') {
+                    self.advance();
+                }
+                if self.digits(10, false)? == 0 {
+                    return Err("Expect digits in exponent.");
+                }
             }
         }
 
-        self.make_token(TokenType::Number)
+        if is_identifier_continue(self.peek()) {
+            return Err("Invalid digit in number literal.");
+        }
+        Ok(())
     }
 
-    fn string(&mut self) -> Token<'src> {
-        while self.peek() != b'"' && !self.is_at_end() {
-            if self.peek() == b'\n' {
-                self.line += 1
-            };
+    /// Consume a run of digits in `radix`, which may be separated by `_`. Returns how many
+    /// digits there were.
+    fn digits(&mut self, radix: u32, after_digit: bool) -> Result<usize, &'static str> {
+        let mut count = 0;
+        let mut after_digit = after_digit;
+        loop {
+            let c = self.peek();
+            if c.is_digit(radix) {
+                count += 1;
+                after_digit = true;
+            } else if c == '_' {
+                if !after_digit || !self.peek_next().is_digit(radix) {
+                    return Err("'_' in a number must be between two digits.");
+                }
+                after_digit = false;
+            } else {
+                return Ok(count);
+            }
             self.advance();
         }
+    }
+
+    /// Consume the next character of a literal, which might be a newline.
+    fn advance_in_literal(&mut self) {
+        if self.peek() == '\n' {
+            self.newline();
+        } else {
+            self.advance();
+        }
+    }
+
+    /// A string, or the part of one up to an interpolated expression. The lexeme starts at
+    /// the opening quote, or if this is a `continuation`, at the `}` of the interpolation
+    /// that came before it.
+    fn string(&mut self, continuation: bool) -> Token<'src> {
+        while self.peek() != '"' && !self.is_at_end() {
+            match self.peek() {
+                // Skip over escaped characters, so `\"` doesn't end the string. The compiler
+                // decodes escape sequences.
+                '\\' => {
+                    self.advance();
+                }
+                '$' if self.peek_next() == '{' => {
+                    self.advance();
+                    self.advance();
+                    self.interpolations.push(0);
+                    return self.make_token(if continuation {
+                        TokenType::InterpolationMid
+                    } else {
+                        TokenType::Interpolation
+                    });
+                }
+                _ => {}
+            }
+            self.advance_in_literal();
+        }
 
         if self.is_at_end() {
             return self.error_token("Unterminated string.");
@@ -229,27 +404,94 @@ impl<'src> Scanner<'src> {
 
         // The closing quote.
         self.advance();
+        self.make_token(if continuation {
+            TokenType::InterpolationEnd
+        } else {
+            TokenType::String
+        })
+    }
+
+    /// A string between triple quotes, whose contents are taken exactly as written.
+    fn raw_string(&mut self) -> Token<'src> {
+        // The rest of the opening quotes.
+        self.advance();
+        self.advance();
+        while !self.src[self.current..].starts_with("\"\"\"") && !self.is_at_end() {
+            self.advance_in_literal();
+        }
+
+        if self.is_at_end() {
+            return self.error_token("Unterminated string.");
+        };
+
+        // The closing quotes.
+        for _ in 0..3 {
+            self.advance();
+        }
         self.make_token(TokenType::String)
     }
 }
 
-#[derive(Clone, Copy)]
+/// Yields every token up to, but not including, the [`TokenType::Eof`] token.
+impl<'src> Iterator for Scanner<'src> {
+    type Item = Token<'src>;
+
+    fn next(&mut self) -> Option<Token<'src>> {
+        let token = self.scan_token();
+        (token.token_type != TokenType::Eof).then_some(token)
+    }
+}
+
+// Once the scanner reaches the end of the source, it keeps returning `Eof`.
+impl FusedIterator for Scanner<'_> {}
+
+/// A piece of the source code, and what kind of token it is.
+#[derive(Clone, Copy, Debug)]
 pub struct Token<'src> {
     pub token_type: TokenType,
-    // start: usize,
-    // length: usize,
+    /// Byte offset of the lexeme in the source.
+    pub start: usize,
+    /// Length of the lexeme in bytes.
+    pub length: usize,
+    /// Line and column (counted in characters, from 1) where the lexeme starts.
     pub line: usize,
+    pub column: usize,
     pub lexeme: &'src str,
 }
 
 impl<'src> Token<'src> {
+    /// A token that doesn't appear in the source, like the implicit `this` in methods.
     pub fn new(token_type: TokenType, line: usize, lexeme: &'src str) -> Token<'src> {
         Token {
             token_type,
+            start: 0,
+            length: 0,
             line,
+            column: 0,
             lexeme,
         }
     }
+
+    pub fn span(&self) -> Span {
+        Span {
+            start: self.start,
+            length: self.length,
+            line: self.line,
+            column: self.column,
+        }
+    }
+}
+
+/// Where some source code is, e.g. the code a compile error is about.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    /// Byte offset of the first byte.
+    pub start: usize,
+    /// Length in bytes.
+    pub length: usize,
+    /// Line and column (counted in characters, from 1) of the first character.
+    pub line: usize,
+    pub column: usize,
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -279,6 +521,13 @@ pub enum TokenType {
     // Literals.
     Identifier,
     String,
+    /// The part of a string before an interpolated expression, up to and including `${`.
+    /// The expression's tokens follow, then the rest of the string.
+    Interpolation,
+    /// The part of a string between two interpolated expressions, from `}` to `${`.
+    InterpolationMid,
+    /// The end of a string after an interpolated expression, from `}` to the closing quote.
+    InterpolationEnd,
     Number,
 
     // Keywords.
@@ -299,16 +548,28 @@ pub enum TokenType {
     Var,
     While,
 
+    // Trivia, only returned by scanners made with `Scanner::with_trivia`.
+    /// A run of spaces, tabs and newlines.
+    Whitespace,
+    /// A `//` comment, without its newline, or a `/* ... */` comment.
+    Comment,
+
     // Special
-    Error,
+    /// Source code that isn't a valid token, and the reason why.
+    Error(&'static str),
     Eof,
 }
 
-fn is_alpha(c: u8) -> bool {
-    c.is_ascii_alphabetic() || c == b'_'
+/// Identifiers follow Unicode's rules (UAX #31), plus Lox allows them to start with `_`.
+fn is_identifier_start(c: char) -> bool {
+    c == '_' || unicode_ident::is_xid_start(c)
+}
+
+fn is_identifier_continue(c: char) -> bool {
+    unicode_ident::is_xid_continue(c)
 }
 
-fn is_digit(c: u8) -> bool {
+fn is_digit(c: char) -> bool {
     c.is_ascii_digit()
 }
 
@@ -319,23 +580,165 @@ mod tests {
     #[test]
     fn lex_simple_statement() {
         let source = "var x = 3";
-        let mut scanner = Scanner::new(source);
-        let mut line: Option<usize> = None;
-        let mut actual_tokens = Vec::new();
-        loop {
-            let token = scanner.scan_token();
-            if Some(token.line) != line {
-                print!("{:4} ", token.line);
-                line = Some(token.line);
-            } else {
-                print!("   | ")
-            }
-            println!("{:2} '{}'", token.token_type as u8, token.lexeme);
-            if token.token_type == TokenType::Eof {
-                break;
-            }
-            actual_tokens.push(token.lexeme);
+        let tokens: Vec<_> = Scanner::new(source).map(|token| token.lexeme).collect();
+        assert_eq!(tokens, vec!["var", "x", "=", "3"])
+    }
+
+    #[test]
+    fn tokens_know_where_they_are() {
+        let spans: Vec<_> = Scanner::new("print 1;\n  \"ab\ncd\" @")
+            .map(|token| (token.token_type, token.span()))
+            .collect();
+        let span = |start, length, line, column| Span {
+            start,
+            length,
+            line,
+            column,
+        };
+        assert_eq!(
+            spans,
+            [
+                (TokenType::Print, span(0, 5, 1, 1)),
+                (TokenType::Number, span(6, 1, 1, 7)),
+                (TokenType::Semicolon, span(7, 1, 1, 8)),
+                (TokenType::String, span(11, 7, 2, 3)),
+                (TokenType::Error("Unexpected character."), span(19, 1, 3, 5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn unicode_identifiers_and_strings() {
+        let tokens: Vec<_> = Scanner::new("var größe = \"日本語\";\n_x1 + é // ✓ done")
+            .map(|token| (token.token_type, token.lexeme))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                (TokenType::Var, "var"),
+                (TokenType::Identifier, "größe"),
+                (TokenType::Equal, "="),
+                (TokenType::String, "\"日本語\""),
+                (TokenType::Semicolon, ";"),
+                (TokenType::Identifier, "_x1"),
+                (TokenType::Plus, "+"),
+                (TokenType::Identifier, "é"),
+            ]
+        );
+    }
+
+    #[test]
+    fn scanning_never_panics() {
+        let sources = [
+            "", "a", "1", "/", "\"", "1.", ".", "é", "✓", "\0", "//", "\"é", "1.é", "a\u{301}",
+            "/*", "/*/", "/* \n", "\"${", "\"${}", "\"${\"", "}", "\"$", "\"${{}\"",
+        ];
+        for source in sources {
+            // Every byte of the source ends up in some token.
+            let lexemes: String = Scanner::with_trivia(source)
+                .map(|token| token.lexeme)
+                .collect();
+            assert_eq!(lexemes, source);
         }
-        assert_eq!(actual_tokens, vec!["var", "x", "=", "3"])
+    }
+
+    #[test]
+    fn trivia_tokens_are_lossless() {
+        let source = "var a = 1; // one\n\t/* two /* three */ */\r\n  print a;/* open";
+        let tokens: Vec<_> = Scanner::with_trivia(source)
+            .map(|token| (token.token_type, token.lexeme))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                (TokenType::Var, "var"),
+                (TokenType::Whitespace, " "),
+                (TokenType::Identifier, "a"),
+                (TokenType::Whitespace, " "),
+                (TokenType::Equal, "="),
+                (TokenType::Whitespace, " "),
+                (TokenType::Number, "1"),
+                (TokenType::Semicolon, ";"),
+                (TokenType::Whitespace, " "),
+                (TokenType::Comment, "// one"),
+                (TokenType::Whitespace, "\n\t"),
+                (TokenType::Comment, "/* two /* three */ */"),
+                (TokenType::Whitespace, "\r\n  "),
+                (TokenType::Print, "print"),
+                (TokenType::Whitespace, " "),
+                (TokenType::Identifier, "a"),
+                (TokenType::Semicolon, ";"),
+                (TokenType::Error("Unterminated block comment."), "/* open"),
+            ]
+        );
+    }
+
+    #[test]
+    fn interpolated_strings_are_split_into_segments() {
+        let source = "\"a ${b + \"c ${d}\"} e ${ {} } \\${f}\"";
+        let tokens: Vec<_> = Scanner::new(source)
+            .map(|token| (token.token_type, token.lexeme))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                (TokenType::Interpolation, "\"a ${"),
+                (TokenType::Identifier, "b"),
+                (TokenType::Plus, "+"),
+                (TokenType::Interpolation, "\"c ${"),
+                (TokenType::Identifier, "d"),
+                (TokenType::InterpolationEnd, "}\""),
+                (TokenType::InterpolationMid, "} e ${"),
+                (TokenType::LeftBrace, "{"),
+                (TokenType::RightBrace, "}"),
+                (TokenType::InterpolationEnd, "} \\${f}\""),
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_are_skipped() {
+        let source = "// one\n  // two\n/* a /* nested\n */ comment */ x /**/ y\n/* open /* */";
+        let tokens: Vec<_> = Scanner::new(source)
+            .map(|token| (token.token_type, token.line))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                (TokenType::Identifier, 4),
+                (TokenType::Identifier, 4),
+                (TokenType::Error("Unterminated block comment."), 5),
+            ]
+        );
+    }
+
+    #[test]
+    fn number_literals() {
+        let source = "12 0xFF 0b1010 0o17 1_000 1.5e-3 2E+10 0x 1e 1__0 0b12 12abc 1_ 3.";
+        let tokens: Vec<_> = Scanner::new(source)
+            .map(|token| (token.token_type, token.lexeme))
+            .collect();
+        let separator = TokenType::Error("'_' in a number must be between two digits.");
+        let invalid = TokenType::Error("Invalid digit in number literal.");
+        assert_eq!(
+            tokens,
+            [
+                (TokenType::Number, "12"),
+                (TokenType::Number, "0xFF"),
+                (TokenType::Number, "0b1010"),
+                (TokenType::Number, "0o17"),
+                (TokenType::Number, "1_000"),
+                (TokenType::Number, "1.5e-3"),
+                (TokenType::Number, "2E+10"),
+                (TokenType::Error("Expect hex digits after '0x'."), "0x"),
+                (TokenType::Error("Expect digits in exponent."), "1e"),
+                (separator, "1__0"),
+                (invalid, "0b12"),
+                (invalid, "12abc"),
+                (separator, "1_"),
+                (TokenType::Number, "3"),
+                (TokenType::Dot, "."),
+            ]
+        );
     }
 }
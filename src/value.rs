@@ -1,46 +1,112 @@
-#[derive(Debug, Clone)]
-pub struct Value(pub f64);
+use std::fmt;
+
+use crate::{
+    heap::Heap,
+    object::{ObjFunction, ObjRef, Object},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    /// A heap-allocated object. Strings are interned, so equal strings have equal handles.
+    Obj(ObjRef),
+}
 
 impl From<f64> for Value {
     fn from(value: f64) -> Self {
-        Self(value)
+        Self::Number(value)
     }
 }
 
-impl Value {
-    pub fn print(&self) {
-        print!("'{}'", self.0);
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
     }
 }
 
-impl std::ops::Sub for Value {
-    type Output = Self;
-
-    fn sub(self, rhs: Self) -> Self::Output {
-        Self(self.0.sub(rhs.0))
+impl From<ObjRef> for Value {
+    fn from(value: ObjRef) -> Self {
+        Self::Obj(value)
     }
 }
 
-impl std::ops::Mul for Value {
-    type Output = Self;
+impl Value {
+    /// Lox treats `nil` and `false` as falsey, and every other value as truthy.
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Self::Nil | Self::Bool(false))
+    }
+
+    /// Describes the value's type, for error messages.
+    pub fn type_name(&self, heap: &Heap) -> &'static str {
+        match self {
+            Self::Nil => "nil",
+            Self::Bool(_) => "a boolean",
+            Self::Number(_) => "a number",
+            Self::Obj(obj) => match heap.get(*obj) {
+                Object::String(_) => "a string",
+                Object::Function(_) | Object::Closure(_) | Object::Native(_) => "a function",
+                Object::Upvalue(_) => "an upvalue",
+                Object::Class(_) => "a class",
+                Object::Instance(_) => "an instance",
+                Object::BoundMethod(_) => "a bound method",
+            },
+        }
+    }
 
-    fn mul(self, rhs: Self) -> Self::Output {
-        Self(self.0.mul(rhs.0))
+    /// Values may point into the heap, so they need it to be printed.
+    pub fn display<'a>(&self, heap: &'a Heap) -> DisplayValue<'a> {
+        DisplayValue { value: *self, heap }
     }
 }
 
-impl std::ops::Div for Value {
-    type Output = Self;
+pub struct DisplayValue<'a> {
+    value: Value,
+    heap: &'a Heap,
+}
 
-    fn div(self, rhs: Self) -> Self::Output {
-        Self(self.0.div(rhs.0))
+impl DisplayValue<'_> {
+    fn fmt_function(&self, function: &ObjFunction, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match function.name {
+            Some(name) => write!(f, "<fn {}>", self.heap.as_str(name).unwrap_or_default()),
+            None => write!(f, "<script>"),
+        }
     }
 }
 
-impl std::ops::Add for Value {
-    type Output = Self;
-
-    fn add(self, rhs: Self) -> Self::Output {
-        Self(self.0.add(rhs.0))
+impl fmt::Display for DisplayValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.value {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Number(n) => write!(f, "{n}"),
+            Value::Obj(obj) => match self.heap.get(obj) {
+                Object::String(s) => write!(f, "{}", s.chars),
+                Object::Function(function) => self.fmt_function(function, f),
+                Object::Closure(closure) => {
+                    let function = self.heap.as_function(closure.function);
+                    self.fmt_function(function.expect("closures wrap functions"), f)
+                }
+                Object::Upvalue(_) => write!(f, "upvalue"),
+                Object::Class(class) => {
+                    write!(f, "{}", self.heap.as_str(class.name).unwrap_or_default())
+                }
+                Object::Instance(instance) => {
+                    let class = self.heap.as_class(instance.class);
+                    let name = class.and_then(|class| self.heap.as_str(class.name));
+                    write!(f, "{} instance", name.unwrap_or_default())
+                }
+                Object::BoundMethod(bound) => {
+                    let closure = self.heap.as_closure(bound.method);
+                    let function = closure.and_then(|c| self.heap.as_function(c.function));
+                    self.fmt_function(function.expect("bound methods wrap closures"), f)
+                }
+                Object::Native(native) => {
+                    let name = self.heap.as_str(native.name).unwrap_or_default();
+                    write!(f, "<native fn {name}>")
+                }
+            },
+        }
     }
 }
@@ -1,142 +1,1495 @@
+use std::{collections::HashMap, fmt, rc::Rc, time::Instant};
+
 use crate::{
     chunk::Chunk,
     compiler::compile,
+    heap::Heap,
+    object::{
+        NativeError, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjNative,
+        ObjRef, ObjUpvalue, Object,
+    },
     opcode::{CouldNotDecodeOpcode, Opcode},
+    tokenizer::Span,
     value::Value,
 };
 
+/// How deeply Lox calls may nest before the VM reports a stack overflow.
+const FRAMES_MAX: usize = 4096;
+/// How many values the stack may hold before a call reports a stack overflow. Each frame can
+/// use up to 256 slots, so deep calls of functions with many locals hit this first.
+const STACK_MAX: usize = 1 << 18;
+
+/// A function call that is in progress.
 #[derive(Debug)]
-pub struct Vm {
-    chunk: Chunk,
-    /// Instruction pointer
+struct CallFrame {
+    closure: ObjRef,
+    /// The closure's function, cached so each instruction fetch doesn't need two lookups.
+    function: ObjRef,
+    /// Instruction pointer into the function's chunk.
     ip: usize,
+    /// Index of the first stack slot this call can use. It holds the function being called.
+    slots: usize,
+}
+
+#[derive(Debug)]
+pub struct Vm {
+    frames: Vec<CallFrame>,
     stack: Vec<Value>,
+    pub(crate) heap: Heap,
+    /// Global variables, keyed by their interned name. These outlive any one call to `interpret`.
+    globals: HashMap<ObjRef, Value>,
+    /// Upvalues still pointing at live stack slots, sorted by slot. Closures that capture the
+    /// same variable share one upvalue, so assignments through either are visible to both.
+    open_upvalues: Vec<ObjRef>,
+    /// The interned string "init", so looking up initializers doesn't need to hash it.
+    init_string: ObjRef,
+    /// Objects allocated by the native function being called. They're kept alive until it
+    /// returns, since nothing else can see them yet.
+    native_roots: Vec<ObjRef>,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Vm {
     pub fn init(&mut self) {
         self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+        self.native_roots.clear();
     }
 
     pub fn new() -> Self {
+        let mut heap = Heap::default();
+        let init_string = heap.intern("init");
         let mut slf = Self {
-            chunk: Default::default(),
-            ip: Default::default(),
+            frames: Default::default(),
             stack: Default::default(),
+            heap,
+            globals: Default::default(),
+            open_upvalues: Default::default(),
+            init_string,
+            native_roots: Vec::new(),
         };
         slf.init();
+        let start = Instant::now();
+        slf.define_native("clock", 0, move |_, _| {
+            Ok(Value::Number(start.elapsed().as_secs_f64()))
+        });
         slf
     }
 
+    /// Expose a Rust function to Lox code as the global `name`. The VM checks that calls pass
+    /// exactly `arity` arguments before running `function`.
+    pub fn define_native<F>(&mut self, name: &str, arity: usize, function: F)
+    where
+        F: Fn(&mut Vm, &[Value]) -> Result<Value, NativeError> + 'static,
+    {
+        let name = self.heap.intern(name);
+        // Keep the name reachable while the native is allocated.
+        self.stack.push(Value::Obj(name));
+        let native = self.alloc(Object::Native(ObjNative {
+            name,
+            arity,
+            function: Rc::new(function),
+        }));
+        self.stack.pop();
+        self.globals.insert(name, Value::Obj(native));
+    }
+
+    /// Allocate a string, e.g. for a native function to return.
+    pub fn new_string(&mut self, chars: impl Into<String>) -> Value {
+        let string = self.intern_owned(chars.into());
+        // While code is running, only natives can call this.
+        if !self.frames.is_empty() {
+            self.native_roots.push(string);
+        }
+        Value::Obj(string)
+    }
+
+    /// The contents of `value`, if it's a string.
+    pub fn as_str(&self, value: Value) -> Option<&str> {
+        match value {
+            Value::Obj(obj) => self.heap.as_str(obj),
+            _ => None,
+        }
+    }
+
+    /// Compile and run `source`.
+    ///
+    /// Natives can't use this to run code of their own, because the VM is busy with the
+    /// call to the native. That returns [`RuntimeErr::AlreadyRunning`], leaving the VM as
+    /// it was.
     pub fn interpret(&mut self, source: &str) -> Result<(), Error> {
-        let chunk = Chunk::default();
-        self.chunk = chunk;
-        self.ip = 0;
-        compile(source, &mut self.chunk)?;
-        self.run()
+        if !self.frames.is_empty() {
+            let trace = self.stack_trace();
+            return Err(Error::Runtime {
+                err: RuntimeErr::AlreadyRunning,
+                line: trace.first().map_or(0, |frame| frame.line),
+                trace,
+            });
+        }
+        self.init();
+        let function = compile(source, self).map_err(Error::Compile)?;
+        let script = self.alloc(Object::Closure(ObjClosure {
+            function,
+            upvalues: Vec::new(),
+        }));
+        self.stack.push(Value::Obj(script));
+        self.call(script, 0)
+            .and_then(|()| self.run())
+            .map_err(|err| self.runtime_error(err))
     }
 
-    fn run(&mut self) -> Result<(), Error> {
+    /// Describe where `err` happened, then reset the VM so it can run more code.
+    fn runtime_error(&mut self, err: RuntimeErr) -> Error {
+        let trace = self.stack_trace();
+        // Closures that escaped, e.g. into globals, must not keep pointing into the old stack.
+        self.close_upvalues(0);
+        self.init();
+        Error::Runtime {
+            err,
+            line: trace.first().map_or(0, |frame| frame.line),
+            trace,
+        }
+    }
+
+    /// The calls in progress, innermost first.
+    fn stack_trace(&self) -> Vec<TraceFrame> {
+        self.frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = self
+                    .heap
+                    .as_function(frame.function)
+                    .expect("frames run functions");
+                TraceFrame {
+                    // The ip has already moved past the failing instruction's opcode.
+                    line: function.chunk.line_at(frame.ip.saturating_sub(1)),
+                    function: function
+                        .name
+                        .map(|name| self.heap.as_str(name).unwrap_or_default().to_owned()),
+                }
+            })
+            .collect()
+    }
+
+    fn run(&mut self) -> Result<(), RuntimeErr> {
         loop {
             if cfg!(feature = "trace") {
                 print!("          ");
                 for slot in &self.stack {
-                    print!("[");
-                    slot.print();
-                    print!("]");
+                    print!("[ '{}' ]", slot.display(&self.heap));
                 }
                 println!();
-                self.chunk.disassemble_instruction(self.ip);
+                self.chunk()
+                    .disassemble_instruction(self.frame().ip, &self.heap);
+            }
+            let mut instruction = self.read_byte();
+            // OP_WIDE widens the constant operand of the instruction after it.
+            let wide = instruction == Opcode::Wide as u8;
+            if wide {
+                instruction = self.read_byte();
             }
-            let instruction = self.read_byte();
             match Opcode::try_from(instruction) {
                 Ok(Opcode::Return) => {
+                    let result = self.pop()?;
+                    let frame = self.frames.pop().expect("a function is always running");
+                    self.close_upvalues(frame.slots);
+                    if self.frames.is_empty() {
+                        // Pop the top-level script itself.
+                        self.pop()?;
+                        return Ok(());
+                    }
+                    // Discard the callee's whole stack window, then hand the result to the caller.
+                    self.stack.truncate(frame.slots);
+                    self.stack.push(result);
+                }
+                Ok(Opcode::Call) => {
+                    let arg_count = self.read_byte() as usize;
+                    let callee = self.peek(arg_count)?;
+                    self.call_value(callee, arg_count)?;
+                }
+                Ok(Opcode::Print) => {
                     let val = self.pop()?;
-                    val.print();
-                    println!();
-                    return Ok(());
+                    println!("{}", val.display(&self.heap));
+                }
+                Ok(Opcode::Pop) => {
+                    self.pop()?;
+                }
+                Ok(Opcode::DefineGlobal) => {
+                    let name = self.read_string(wide);
+                    let val = self.pop()?;
+                    self.globals.insert(name, val);
+                }
+                Ok(Opcode::GetGlobal) => {
+                    let name = self.read_string(wide);
+                    let Some(&val) = self.globals.get(&name) else {
+                        return Err(self.undefined_variable(name));
+                    };
+                    self.stack.push(val);
+                }
+                Ok(Opcode::GetLocal) => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    self.stack.push(self.stack[slot]);
+                }
+                Ok(Opcode::SetLocal) => {
+                    let slot = self.frame().slots + self.read_byte() as usize;
+                    // Assignment is an expression, so leave the value on the stack.
+                    self.stack[slot] = self.peek(0)?;
+                }
+                Ok(Opcode::Jump) => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip += offset as usize;
+                }
+                Ok(Opcode::JumpIfFalse) => {
+                    let offset = self.read_short();
+                    // The condition stays on the stack; the compiler emits a pop for it.
+                    if self.peek(0)?.is_falsey() {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                Ok(Opcode::Loop) => {
+                    let offset = self.read_short();
+                    self.frame_mut().ip -= offset as usize;
+                }
+                Ok(Opcode::Closure) => {
+                    let Value::Obj(function) = self.read_constant(wide) else {
+                        unreachable!("OP_CLOSURE's operand is always a function");
+                    };
+                    let upvalue_count = self.function(function).upvalue_count;
+                    let mut upvalues = Vec::with_capacity(upvalue_count);
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        let upvalue = if is_local {
+                            self.capture_upvalue(self.frame().slots + index)
+                        } else {
+                            self.closure(self.frame().closure).upvalues[index]
+                        };
+                        upvalues.push(upvalue);
+                    }
+                    let closure = self.alloc(Object::Closure(ObjClosure { function, upvalues }));
+                    self.stack.push(Value::Obj(closure));
+                }
+                Ok(Opcode::GetUpvalue) => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.closure(self.frame().closure).upvalues[slot];
+                    let val = match self.heap.as_upvalue(upvalue) {
+                        Some(ObjUpvalue::Open(i)) => self.stack[*i],
+                        Some(ObjUpvalue::Closed(val)) => *val,
+                        None => unreachable!("closures only capture upvalues"),
+                    };
+                    self.stack.push(val);
+                }
+                Ok(Opcode::SetUpvalue) => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.closure(self.frame().closure).upvalues[slot];
+                    // Assignment is an expression, so leave the value on the stack.
+                    let val = self.peek(0)?;
+                    match self.heap.get_mut(upvalue) {
+                        Object::Upvalue(ObjUpvalue::Open(i)) => self.stack[*i] = val,
+                        Object::Upvalue(ObjUpvalue::Closed(closed)) => *closed = val,
+                        _ => unreachable!("closures only capture upvalues"),
+                    }
+                }
+                Ok(Opcode::CloseUpvalue) => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop()?;
+                }
+                Ok(Opcode::Class) => {
+                    let name = self.read_string(wide);
+                    let class = self.alloc(Object::Class(ObjClass::new(name)));
+                    self.stack.push(Value::Obj(class));
+                }
+                Ok(Opcode::Method) => {
+                    let name = self.read_string(wide);
+                    let method = self.peek(0)?;
+                    let Value::Obj(class) = self.peek(1)? else {
+                        unreachable!("methods are only defined on classes");
+                    };
+                    let Object::Class(object) = self.heap.get_mut(class) else {
+                        unreachable!("methods are only defined on classes");
+                    };
+                    object.methods.insert(name, method);
+                    self.heap.remeasure(class);
+                    self.pop()?;
+                }
+                Ok(Opcode::Inherit) => {
+                    let superclass = self.peek(1)?;
+                    let Some(methods) = self.as_class(superclass).map(|c| c.methods.clone()) else {
+                        return Err(RuntimeErr::SuperclassMustBeClass {
+                            found: superclass.type_name(&self.heap),
+                        });
+                    };
+                    let Value::Obj(subclass) = self.peek(0)? else {
+                        unreachable!("only classes inherit");
+                    };
+                    // Copy-down inheritance: the subclass starts with all the superclass's
+                    // methods, and its own method declarations overwrite them.
+                    let Object::Class(object) = self.heap.get_mut(subclass) else {
+                        unreachable!("only classes inherit");
+                    };
+                    object.methods.extend(methods);
+                    self.heap.remeasure(subclass);
+                    self.pop()?;
+                }
+                Ok(Opcode::GetSuper) => {
+                    let name = self.read_string(wide);
+                    let Value::Obj(superclass) = self.pop()? else {
+                        unreachable!("super is always a class");
+                    };
+                    self.bind_method(superclass, name)?;
+                }
+                Ok(Opcode::GetProperty) => {
+                    let name = self.read_string(wide);
+                    let receiver = self.peek(0)?;
+                    let Some(instance) = self.as_instance(receiver) else {
+                        return Err(RuntimeErr::OnlyInstancesHaveProperties {
+                            found: receiver.type_name(&self.heap),
+                        });
+                    };
+                    // Fields shadow methods.
+                    if let Some(&value) = instance.fields.get(&name) {
+                        self.pop()?;
+                        self.stack.push(value);
+                    } else {
+                        self.bind_method(instance.class, name)?;
+                    }
+                }
+                Ok(Opcode::SetProperty) => {
+                    let name = self.read_string(wide);
+                    let target = self.peek(1)?;
+                    if self.as_instance(target).is_none() {
+                        return Err(RuntimeErr::OnlyInstancesHaveFields {
+                            found: target.type_name(&self.heap),
+                        });
+                    }
+                    let Value::Obj(instance) = target else {
+                        unreachable!("checked above");
+                    };
+                    let value = self.pop()?;
+                    if let Object::Instance(object) = self.heap.get_mut(instance) {
+                        object.fields.insert(name, value);
+                    }
+                    self.heap.remeasure(instance);
+                    // Replace the instance with the assigned value, since assignment is an expression.
+                    self.pop()?;
+                    self.stack.push(value);
+                }
+                Ok(Opcode::SetGlobal) => {
+                    let name = self.read_string(wide);
+                    // Assignment is an expression, so leave the value on the stack.
+                    let val = self.peek(0)?;
+                    let Some(slot) = self.globals.get_mut(&name) else {
+                        return Err(self.undefined_variable(name));
+                    };
+                    *slot = val;
                 }
                 Ok(Opcode::Negate) => {
-                    let x = self.stack.pop().unwrap();
-                    let x = Value::from(-x.0);
-                    self.stack.push(x);
+                    let Value::Number(x) = self.pop()? else {
+                        return Err(RuntimeErr::OperandMustBeNumber);
+                    };
+                    self.stack.push(Value::Number(-x));
                 }
                 Ok(Opcode::Constant) => {
-                    let constant = self.read_constant().clone();
+                    let constant = self.read_constant(wide);
                     self.stack.push(constant);
                 }
-                Ok(Opcode::Add) => {
-                    let (a, b) = self.pop_two()?;
-                    self.do_then_push(a, b, std::ops::Add::add)
+                Ok(Opcode::ConstantLong) => {
+                    let constant = self.read_constant(true);
+                    self.stack.push(constant);
                 }
-                Ok(Opcode::Sub) => {
-                    let (a, b) = self.pop_two()?;
-                    self.do_then_push(a, b, std::ops::Sub::sub)
+                Ok(Opcode::Wide) => unreachable!("OP_WIDE only prefixes other instructions"),
+                Ok(Opcode::Nil) => self.stack.push(Value::Nil),
+                Ok(Opcode::True) => self.stack.push(Value::Bool(true)),
+                Ok(Opcode::False) => self.stack.push(Value::Bool(false)),
+                Ok(Opcode::ToString) => {
+                    let value = self.pop()?;
+                    let string = if self.as_str(value).is_some() {
+                        value
+                    } else {
+                        let chars = value.display(&self.heap).to_string();
+                        self.new_string(chars)
+                    };
+                    self.stack.push(string);
                 }
-                Ok(Opcode::Mul) => {
-                    let (a, b) = self.pop_two()?;
-                    self.do_then_push(a, b, std::ops::Mul::mul)
+                Ok(Opcode::Not) => {
+                    let x = self.pop()?;
+                    self.stack.push(Value::Bool(x.is_falsey()));
                 }
-                Ok(Opcode::Div) => {
+                Ok(Opcode::Equal) => {
                     let (a, b) = self.pop_two()?;
-                    self.do_then_push(a, b, std::ops::Div::div)
+                    self.stack.push(Value::Bool(a == b));
                 }
-                Err(e) => return Err(RuntimeErr::from(e).into()),
+                Ok(Opcode::Greater) => self.binary_op(|a, b| Value::Bool(a > b))?,
+                Ok(Opcode::Less) => self.binary_op(|a, b| Value::Bool(a < b))?,
+                Ok(Opcode::Add) => self.add()?,
+                Ok(Opcode::Sub) => self.binary_op(|a, b| Value::Number(a - b))?,
+                Ok(Opcode::Mul) => self.binary_op(|a, b| Value::Number(a * b))?,
+                Ok(Opcode::Div) => self.binary_op(|a, b| Value::Number(a / b))?,
+                Err(e) => return Err(e.into()),
             }
         }
     }
 
-    fn do_then_push<Op>(&mut self, a: Value, b: Value, op: Op)
+    /// Allocate an object, collecting garbage first if the heap has grown enough.
+    /// Anything the new object refers to is kept alive by the collection.
+    pub(crate) fn alloc(&mut self, object: Object) -> ObjRef {
+        if self.heap.should_collect() {
+            self.heap.mark_references(&object);
+            self.collect_garbage();
+        }
+        self.heap.alloc(object)
+    }
+
+    /// Intern a string, collecting garbage first if the heap has grown enough.
+    pub(crate) fn intern_owned(&mut self, chars: String) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.intern_owned(chars)
+    }
+
+    /// Free every object that the running program can no longer reach. Callers with
+    /// roots of their own, like the compiler, must mark them before calling this.
+    pub(crate) fn collect_garbage(&mut self) {
+        self.mark_roots();
+        self.heap.collect();
+    }
+
+    fn mark_roots(&mut self) {
+        for &value in &self.stack {
+            self.heap.mark_value(value);
+        }
+        for frame in &self.frames {
+            self.heap.mark_object(frame.closure);
+        }
+        for &upvalue in &self.open_upvalues {
+            self.heap.mark_object(upvalue);
+        }
+        for (&name, &value) in &self.globals {
+            self.heap.mark_object(name);
+            self.heap.mark_value(value);
+        }
+        self.heap.mark_object(self.init_string);
+        for &obj in &self.native_roots {
+            self.heap.mark_object(obj);
+        }
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("a function is always running")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames
+            .last_mut()
+            .expect("a function is always running")
+    }
+
+    /// The chunk of the function that is currently running.
+    fn chunk(&self) -> &Chunk {
+        &self.function(self.frame().function).chunk
+    }
+
+    fn closure(&self, closure: ObjRef) -> &ObjClosure {
+        self.heap
+            .as_closure(closure)
+            .expect("call frames always hold closures")
+    }
+
+    fn function(&self, function: ObjRef) -> &ObjFunction {
+        self.heap
+            .as_function(function)
+            .expect("call frames always hold functions")
+    }
+
+    fn as_class(&self, value: Value) -> Option<&ObjClass> {
+        match value {
+            Value::Obj(obj) => self.heap.as_class(obj),
+            _ => None,
+        }
+    }
+
+    fn as_instance(&self, value: Value) -> Option<&ObjInstance> {
+        match value {
+            Value::Obj(obj) => self.heap.as_instance(obj),
+            _ => None,
+        }
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), RuntimeErr> {
+        let not_callable = RuntimeErr::NotCallable {
+            found: callee.type_name(&self.heap),
+        };
+        let Value::Obj(obj) = callee else {
+            return Err(not_callable);
+        };
+        // The callee's stack slot, just below its arguments.
+        let callee_slot = self.stack.len() - arg_count - 1;
+
+        if self.heap.as_closure(obj).is_some() {
+            return self.call(obj, arg_count);
+        }
+        if let Some(bound) = self.heap.as_bound_method(obj) {
+            // The method sees the receiver as `this`, in slot zero.
+            let method = bound.method;
+            self.stack[callee_slot] = bound.receiver;
+            return self.call(method, arg_count);
+        }
+        if let Some(native) = self.heap.as_native(obj) {
+            if arg_count != native.arity {
+                return Err(RuntimeErr::WrongArity {
+                    expected: native.arity,
+                    got: arg_count,
+                });
+            }
+            let function = Rc::clone(&native.function);
+            let name = native.name;
+            // The arguments stay on the stack while the native runs, so they're still rooted
+            // if it allocates.
+            let args = self.stack[callee_slot + 1..].to_vec();
+            let result = function(self, &args).map_err(|source| match source.downcast() {
+                Ok(err) => *err,
+                Err(source) => RuntimeErr::Native {
+                    name: self.heap.as_str(name).unwrap_or_default().to_owned(),
+                    source,
+                },
+            })?;
+            // The result is about to be on the stack, which keeps it alive from here on.
+            self.native_roots.clear();
+            self.stack.truncate(callee_slot);
+            self.stack.push(result);
+            return Ok(());
+        }
+        if let Some(class) = self.heap.as_class(obj) {
+            let initializer = class.methods.get(&self.init_string).copied();
+            // The class is still on the stack, so it's safe to allocate.
+            let instance = self.alloc(Object::Instance(ObjInstance::new(obj)));
+            self.stack[callee_slot] = Value::Obj(instance);
+            return match initializer {
+                Some(Value::Obj(initializer)) => self.call(initializer, arg_count),
+                _ if arg_count != 0 => Err(RuntimeErr::WrongArity {
+                    expected: 0,
+                    got: arg_count,
+                }),
+                _ => Ok(()),
+            };
+        }
+        Err(not_callable)
+    }
+
+    /// Replace the instance on top of the stack with its class's method `name`, bound to it.
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<(), RuntimeErr> {
+        let class = self.heap.as_class(class).expect("instances have classes");
+        let Some(&Value::Obj(method)) = class.methods.get(&name) else {
+            return Err(RuntimeErr::UndefinedProperty {
+                name: self.heap.as_str(name).unwrap_or_default().to_owned(),
+            });
+        };
+        let receiver = self.peek(0).expect("the receiver is on the stack");
+        let bound = self.alloc(Object::BoundMethod(ObjBoundMethod { receiver, method }));
+        self.pop().expect("the receiver is on the stack");
+        self.stack.push(Value::Obj(bound));
+        Ok(())
+    }
+
+    /// Push a call frame for `closure`, whose arguments are already on the stack.
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> Result<(), RuntimeErr> {
+        let function = self.closure(closure).function;
+        let arity = self.function(function).arity;
+        if arg_count != arity {
+            return Err(RuntimeErr::WrongArity {
+                expected: arity,
+                got: arg_count,
+            });
+        }
+        if self.frames.len() == FRAMES_MAX || self.stack.len() > STACK_MAX {
+            return Err(RuntimeErr::StackOverflow);
+        }
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slots: self.stack.len() - arg_count - 1,
+        });
+        Ok(())
+    }
+
+    /// Get an upvalue pointing at this stack slot, reusing an open one if it exists.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let position = self.open_upvalues.binary_search_by_key(&slot, |&upvalue| {
+            match self.heap.as_upvalue(upvalue) {
+                Some(ObjUpvalue::Open(i)) => *i,
+                _ => unreachable!("open upvalues are always open"),
+            }
+        });
+        match position {
+            Ok(existing) => self.open_upvalues[existing],
+            Err(insert_at) => {
+                let upvalue = self.alloc(Object::Upvalue(ObjUpvalue::Open(slot)));
+                self.open_upvalues.insert(insert_at, upvalue);
+                upvalue
+            }
+        }
+    }
+
+    /// Close every open upvalue pointing at `last` or any slot above it, by moving the
+    /// variable off the stack and into the upvalue.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let Object::Upvalue(state) = self.heap.get_mut(upvalue) else {
+                unreachable!("open upvalues are always upvalues");
+            };
+            let ObjUpvalue::Open(slot) = *state else {
+                unreachable!("open upvalues are always open");
+            };
+            if slot < last {
+                break;
+            }
+            *state = ObjUpvalue::Closed(self.stack[slot]);
+            self.open_upvalues.pop();
+        }
+    }
+
+    /// Add two numbers, or concatenate two strings.
+    fn add(&mut self) -> Result<(), RuntimeErr> {
+        let (a, b) = self.pop_two()?;
+        let result = match (a, b) {
+            (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+            (Value::Obj(a), Value::Obj(b)) => {
+                let (Some(a), Some(b)) = (self.heap.as_str(a), self.heap.as_str(b)) else {
+                    return Err(RuntimeErr::BadAddOperands);
+                };
+                let concatenated = format!("{a}{b}");
+                Value::Obj(self.intern_owned(concatenated))
+            }
+            _ => return Err(RuntimeErr::BadAddOperands),
+        };
+        self.stack.push(result);
+        Ok(())
+    }
+
+    /// Pop two numeric operands, apply `op` to them and push the result.
+    fn binary_op<Op>(&mut self, op: Op) -> Result<(), RuntimeErr>
     where
-        Op: Fn(Value, Value) -> Value,
+        Op: Fn(f64, f64) -> Value,
     {
+        let (a, b) = self.pop_two()?;
+        let (Value::Number(a), Value::Number(b)) = (a, b) else {
+            return Err(RuntimeErr::OperandsMustBeNumbers);
+        };
         self.stack.push(op(a, b));
+        Ok(())
     }
 
-    fn pop(&mut self) -> Result<Value, CompileErr> {
-        let val = self.stack.pop().ok_or(CompileErr::StackEmpty)?;
-        Ok(val.clone())
+    fn pop(&mut self) -> Result<Value, RuntimeErr> {
+        self.stack.pop().ok_or(RuntimeErr::StackEmpty)
     }
 
-    fn pop_two(&mut self) -> Result<(Value, Value), CompileErr> {
+    /// Look at a value `distance` slots down from the top of the stack, without popping it.
+    fn peek(&self, distance: usize) -> Result<Value, RuntimeErr> {
+        let i = self.stack.len().checked_sub(distance + 1);
+        i.map(|i| self.stack[i]).ok_or(RuntimeErr::StackEmpty)
+    }
+
+    fn pop_two(&mut self) -> Result<(Value, Value), RuntimeErr> {
         let b = self.pop()?;
         let a = self.pop()?;
         Ok((a, b))
     }
 
-    fn read_constant(&mut self) -> &Value {
-        let i = self.read_byte();
-        &self.chunk.constants[i as usize]
+    /// Read a constant addressed by a one-byte operand, or a three-byte one if `wide`.
+    fn read_constant(&mut self, wide: bool) -> Value {
+        let i = if wide {
+            u32::from_be_bytes([0, self.read_byte(), self.read_byte(), self.read_byte()]) as usize
+        } else {
+            self.read_byte() as usize
+        };
+        self.chunk().constants[i]
+    }
+
+    /// Read a constant which the compiler guarantees is a string, e.g. a variable name.
+    fn read_string(&mut self, wide: bool) -> ObjRef {
+        match self.read_constant(wide) {
+            Value::Obj(obj) => obj,
+            other => unreachable!("expected a string constant, found {other:?}"),
+        }
+    }
+
+    fn undefined_variable(&self, name: ObjRef) -> RuntimeErr {
+        RuntimeErr::UndefinedVariable {
+            name: self.heap.as_str(name).unwrap_or_default().to_owned(),
+        }
     }
 
     fn read_byte(&mut self) -> u8 {
-        let byte = self.chunk.code[self.ip];
-        self.ip += 1;
+        let frame = self
+            .frames
+            .last_mut()
+            .expect("a function is always running");
+        let function = self.heap.as_function(frame.function);
+        let byte = function
+            .expect("call frames always hold functions")
+            .chunk
+            .code[frame.ip];
+        frame.ip += 1;
         byte
     }
+
+    /// Read a big-endian two-byte operand.
+    fn read_short(&mut self) -> u16 {
+        u16::from_be_bytes([self.read_byte(), self.read_byte()])
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[allow(dead_code)]
-    #[error("Compile error: {0}")]
-    Compile(#[from] CompileErr),
-    #[error("Runtime error: {0}")]
-    Runtime(#[from] RuntimeErr),
+    #[error("{}", list_errors(.0))]
+    Compile(Vec<CompileErr>),
+    #[error("Runtime error: {err}\n{}", list_frames(.trace))]
+    Runtime {
+        err: RuntimeErr,
+        /// The line of the instruction that failed.
+        line: usize,
+        /// The calls that were in progress, innermost first.
+        trace: Vec<TraceFrame>,
+    },
+}
+
+/// A function call that was running when a runtime error happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    /// The line that the call had reached.
+    pub line: usize,
+    /// The function's name, or None for the top-level script.
+    pub function: Option<String>,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {name}()", self.line),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
+
+/// How many frames to show at each end of a long stack trace.
+const TRACE_ENDS: usize = 10;
+
+/// Show each frame on its own line. The middle of a deep trace, like a stack overflow's, is
+/// left out.
+fn list_frames(trace: &[TraceFrame]) -> String {
+    let mut lines: Vec<_> = trace.iter().map(ToString::to_string).collect();
+    if lines.len() > 2 * TRACE_ENDS + 1 {
+        let hidden = lines.len() - 2 * TRACE_ENDS;
+        lines.splice(
+            TRACE_ENDS..lines.len() - TRACE_ENDS,
+            [format!("... {hidden} more frames ...")],
+        );
+    }
+    lines.join("\n")
+}
+
+/// Show each compile error on its own line.
+fn list_errors(errors: &[CompileErr]) -> String {
+    let lines: Vec<_> = errors
+        .iter()
+        .map(|err| format!("Compile error: {err}"))
+        .collect();
+    lines.join("\n")
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum CompileErr {
-    #[error("tried to read from the stack, but it was empty")]
-    StackEmpty,
-    #[error("unexpected or invalid token in your source code at line {line}")]
-    BadToken { line: usize },
-    #[error("error at line {line}: {msg}")]
-    Other { line: usize, msg: &'static str },
+    /// The scanner couldn't make sense of some source code.
+    #[error("error at {}:{}: {msg}", .span.line, .span.column)]
+    BadToken { span: Span, msg: &'static str },
+    #[error("error at {}:{}: {msg}", .span.line, .span.column)]
+    Other { span: Span, msg: &'static str },
+}
+
+impl CompileErr {
+    /// The source code this error is about.
+    pub fn span(&self) -> Span {
+        match self {
+            Self::BadToken { span, .. } | Self::Other { span, .. } => *span,
+        }
+    }
+
+    /// Describe the error along with the line of `source` it's on, underlining the exact code
+    /// at fault, e.g.
+    ///
+    /// ```text
+    /// error: Expect expression.
+    ///  --> 1:9
+    ///   |
+    /// 1 | var a = ;
+    ///   |         ^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let (Self::BadToken { span, msg } | Self::Other { span, msg }) = self;
+        // Tokens can span lines, e.g. unterminated strings. Only show the first.
+        let start = span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |i| start + i);
+        let text = source[line_start..line_end].trim_end_matches('\r');
+        let end = (start + span.length)
+            .min(line_start + text.len())
+            .max(start);
+
+        // Pad with the same whitespace as the source line, so tabs line up.
+        let padding: String = source[line_start..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let underline = "^".repeat(source[start..end].chars().count().max(1));
+        let line = span.line.to_string();
+        let gutter = " ".repeat(line.len());
+        format!(
+            "error: {msg}\n{gutter}--> {line}:{}\n{gutter} |\n{line} | {text}\n{gutter} | {padding}{underline}",
+            span.column
+        )
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum RuntimeErr {
     #[error("{0}")]
     CouldNotDecodeOpcode(#[from] CouldNotDecodeOpcode),
+    #[error("Operand must be a number.")]
+    OperandMustBeNumber,
+    #[error("Operands must be numbers.")]
+    OperandsMustBeNumbers,
+    #[error("Operands must be two numbers or two strings.")]
+    BadAddOperands,
+    #[error("Undefined variable '{name}'.")]
+    UndefinedVariable { name: String },
+    #[error("Can only call functions and classes, not {found}.")]
+    NotCallable { found: &'static str },
+    #[error("Only instances have properties, not {found}.")]
+    OnlyInstancesHaveProperties { found: &'static str },
+    #[error("Only instances have fields, not {found}.")]
+    OnlyInstancesHaveFields { found: &'static str },
+    #[error("Superclass must be a class, not {found}.")]
+    SuperclassMustBeClass { found: &'static str },
+    #[error("Undefined property '{name}'.")]
+    UndefinedProperty { name: String },
+    #[error("Expected {expected} arguments but got {got}.")]
+    WrongArity { expected: usize, got: usize },
+    #[error("Stack overflow.")]
+    StackOverflow,
+    #[error("Tried to read from the stack, but it was empty.")]
+    StackEmpty,
+    #[error("Can't run more code while the VM is running, e.g. from a native function.")]
+    AlreadyRunning,
+    #[error("{source} (in native fn {name})")]
+    Native { name: String, source: NativeError },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(source: &str) -> Result<(), Error> {
+        Vm::new().interpret(source)
+    }
+
+    #[test]
+    fn type_errors_are_reported() {
+        assert!(matches!(
+            run("-true;"),
+            Err(Error::Runtime {
+                err: RuntimeErr::OperandMustBeNumber,
+                ..
+            })
+        ));
+        assert!(matches!(
+            run("1 - nil;"),
+            Err(Error::Runtime {
+                err: RuntimeErr::OperandsMustBeNumbers,
+                ..
+            })
+        ));
+        assert!(matches!(
+            run("\"a\" + 1;"),
+            Err(Error::Runtime {
+                err: RuntimeErr::BadAddOperands,
+                ..
+            })
+        ));
+        assert!(matches!(
+            run("false < 1;"),
+            Err(Error::Runtime {
+                err: RuntimeErr::OperandsMustBeNumbers,
+                ..
+            })
+        ));
+    }
+
+    fn global(vm: &mut Vm, name: &str) -> Value {
+        let name = vm.heap.intern(name);
+        vm.globals[&name]
+    }
+
+    #[test]
+    fn globals_persist_across_interpret_calls() {
+        let mut vm = Vm::new();
+        vm.interpret("var a = 1; var b;").unwrap();
+        vm.interpret("b = a = a + 2; var c = \"x\" + \"y\";")
+            .unwrap();
+        assert_eq!(global(&mut vm, "a"), Value::Number(3.0));
+        assert_eq!(global(&mut vm, "b"), Value::Number(3.0));
+        let Value::Obj(c) = global(&mut vm, "c") else {
+            panic!("expected a string");
+        };
+        assert_eq!(vm.heap.as_str(c), Some("xy"));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn undefined_globals_are_errors() {
+        let err = run("print nope;").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Runtime { err: RuntimeErr::UndefinedVariable { ref name }, .. } if name == "nope"
+        ));
+        let err = run("nope = 1;").unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Runtime {
+                err: RuntimeErr::UndefinedVariable { .. },
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn locals_shadow_globals() {
+        let mut vm = Vm::new();
+        vm.interpret(
+            "var a = 1; var b; var c;
+            {
+                var a = 10;
+                {
+                    var d = a + 5;
+                    var a = d * 2;
+                    b = a;
+                }
+                c = a;
+            }",
+        )
+        .unwrap();
+        assert_eq!(global(&mut vm, "a"), Value::Number(1.0));
+        assert_eq!(global(&mut vm, "b"), Value::Number(30.0));
+        assert_eq!(global(&mut vm, "c"), Value::Number(10.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn control_flow() {
+        let mut vm = Vm::new();
+        vm.interpret(
+            "var fizz = 0; var total = 0;
+            for (var i = 1; i <= 10; i = i + 1) {
+                if (i == 3 or i == 6 or i == 9) fizz = fizz + 1;
+                else total = total + i;
+            }
+            var n = 0;
+            while (n < 5) n = n + 1;
+            var short = false and undefined;
+            var either = nil or \"default\";
+            var neither = 1 and 2;",
+        )
+        .unwrap();
+        assert_eq!(global(&mut vm, "fizz"), Value::Number(3.0));
+        assert_eq!(global(&mut vm, "total"), Value::Number(37.0));
+        assert_eq!(global(&mut vm, "n"), Value::Number(5.0));
+        assert_eq!(global(&mut vm, "short"), Value::Bool(false));
+        let Value::Obj(either) = global(&mut vm, "either") else {
+            panic!("expected a string");
+        };
+        assert_eq!(vm.heap.as_str(either), Some("default"));
+        assert_eq!(global(&mut vm, "neither"), Value::Number(2.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn functions_and_recursion() {
+        let mut vm = Vm::new();
+        vm.interpret(
+            "fun fib(n) {
+                if (n < 2) return n;
+                return fib(n - 2) + fib(n - 1);
+            }
+            fun noReturn() {}
+            var f = fib(10);
+            var nothing = noReturn();",
+        )
+        .unwrap();
+        assert_eq!(global(&mut vm, "f"), Value::Number(55.0));
+        assert_eq!(global(&mut vm, "nothing"), Value::Nil);
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn call_errors() {
+        assert!(matches!(
+            run("fun f(a, b) {} f(1);"),
+            Err(Error::Runtime {
+                err: RuntimeErr::WrongArity {
+                    expected: 2,
+                    got: 1
+                },
+                ..
+            })
+        ));
+        assert!(matches!(
+            run("var x = 1; x();"),
+            Err(Error::Runtime {
+                err: RuntimeErr::NotCallable { found: "a number" },
+                ..
+            })
+        ));
+        assert!(matches!(
+            run("fun forever(n) { return forever(n + 1); } forever(0);"),
+            Err(Error::Runtime {
+                err: RuntimeErr::StackOverflow,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn deep_recursion_only_overflows_when_unbounded() {
+        let mut vm = Vm::new();
+        vm.interpret(
+            "fun count(n) { if (n == 0) return 0; return 1 + count(n - 1); }
+             var depth = count(1000);",
+        )
+        .unwrap();
+        assert_eq!(global(&mut vm, "depth"), Value::Number(1000.0));
+
+        // Many locals per call run out of stack slots before running out of frames.
+        let locals: Vec<String> = (0..200).map(|i| format!("var l{i} = n;")).collect();
+        let source = format!(
+            "fun deep(n) {{ {} return deep(n + 1); }} deep(0);",
+            locals.concat()
+        );
+        assert!(matches!(
+            run(&source),
+            Err(Error::Runtime {
+                err: RuntimeErr::StackOverflow,
+                ref trace,
+                ..
+            }) if trace.len() < FRAMES_MAX
+        ));
+
+        let err = run("fun forever(n) { return forever(n + 1); } forever(0);").unwrap_err();
+        let message = err.to_string();
+        let hidden = format!("... {} more frames ...", FRAMES_MAX - 2 * TRACE_ENDS);
+        assert!(message.contains(&hidden), "{message}");
+        assert_eq!(message.lines().count(), 2 * TRACE_ENDS + 2);
+    }
+
+    #[test]
+    fn closures_capture_variables() {
+        let mut vm = Vm::new();
+        vm.interpret(
+            "fun makeCounter() {
+                var count = 0;
+                fun increment() {
+                    count = count + 1;
+                    return count;
+                }
+                return increment;
+            }
+            var counter = makeCounter();
+            counter();
+            var two = counter();
+            var fresh = makeCounter()();
+
+            fun outer() {
+                var x = 1;
+                fun middle() {
+                    fun inner() { return x; }
+                    return inner;
+                }
+                x = 2;
+                return middle();
+            }
+            var nested = outer()();",
+        )
+        .unwrap();
+        assert_eq!(global(&mut vm, "two"), Value::Number(2.0));
+        assert_eq!(global(&mut vm, "fresh"), Value::Number(1.0));
+        assert_eq!(global(&mut vm, "nested"), Value::Number(2.0));
+        assert!(vm.open_upvalues.is_empty());
+    }
+
+    #[test]
+    fn loop_variables_are_captured_per_iteration() {
+        let mut vm = Vm::new();
+        vm.interpret(
+            "var first; var second;
+            for (var i = 0; i < 2; i = i + 1) {
+                var j = i;
+                fun get() { return j; }
+                if (first == nil) first = get; else second = get;
+            }
+            var a = first();
+            var b = second();
+            var shared;
+            {
+                var v = \"before\";
+                fun set() { v = \"after\"; }
+                fun get() { return v; }
+                set();
+                shared = get;
+            }
+            var c = shared();",
+        )
+        .unwrap();
+        assert_eq!(global(&mut vm, "a"), Value::Number(0.0));
+        assert_eq!(global(&mut vm, "b"), Value::Number(1.0));
+        let Value::Obj(c) = global(&mut vm, "c") else {
+            panic!("expected a string");
+        };
+        assert_eq!(vm.heap.as_str(c), Some("after"));
+    }
+
+    #[test]
+    fn garbage_is_collected() {
+        let mut vm = Vm::new();
+        vm.interpret(
+            "var kept = \"kept\";
+            fun makeClosure(n) {
+                var s = \"value \" + n;
+                fun get() { return s; }
+                return get;
+            }
+            var closure = makeClosure(\"first\");
+            for (var i = 0; i < 100; i = i + 1) {
+                var garbage = makeClosure(\"x\" + \"y\");
+            }",
+        )
+        .unwrap();
+        let before = vm.heap.bytes_allocated();
+        vm.collect_garbage();
+        assert!(vm.heap.bytes_allocated() < before);
+
+        // Everything reachable from globals survived.
+        vm.interpret("var result = kept + \" \" + closure();")
+            .unwrap();
+        let Value::Obj(result) = global(&mut vm, "result") else {
+            panic!("expected a string");
+        };
+        assert_eq!(vm.heap.as_str(result), Some("kept value first"));
+    }
+
+    #[test]
+    fn classes_and_methods() {
+        let mut vm = Vm::new();
+        vm.interpret(
+            "class Point {
+                init(x, y) {
+                    this.x = x;
+                    this.y = y;
+                }
+                sum() { return this.x + this.y; }
+                adder() {
+                    fun add(n) { return this.sum() + n; }
+                    return add;
+                }
+            }
+            var p = Point(1, 2);
+            p.x = 10;
+            var sum = p.sum();
+            var bound = p.sum;
+            p.y = 20;
+            var later = bound();
+            var closure = p.adder()(100);
+            var reinit = p.init(3, 4) == p;
+            class Empty {}
+            var e = Empty();
+            e.field = \"set\";
+            var field = e.field;",
+        )
+        .unwrap();
+        assert_eq!(global(&mut vm, "sum"), Value::Number(12.0));
+        assert_eq!(global(&mut vm, "later"), Value::Number(30.0));
+        assert_eq!(global(&mut vm, "closure"), Value::Number(130.0));
+        assert_eq!(global(&mut vm, "reinit"), Value::Bool(true));
+        let Value::Obj(field) = global(&mut vm, "field") else {
+            panic!("expected a string");
+        };
+        assert_eq!(vm.heap.as_str(field), Some("set"));
+    }
+
+    #[test]
+    fn class_errors() {
+        assert!(matches!(
+            run("class A {} A().nope;"),
+            Err(Error::Runtime { err: RuntimeErr::UndefinedProperty { ref name }, .. }) if name == "nope"
+        ));
+        assert!(matches!(
+            run("class A { init(a) {} } A();"),
+            Err(Error::Runtime {
+                err: RuntimeErr::WrongArity {
+                    expected: 1,
+                    got: 0
+                },
+                ..
+            })
+        ));
+        assert!(matches!(
+            run("class A {} A(1);"),
+            Err(Error::Runtime {
+                err: RuntimeErr::WrongArity {
+                    expected: 0,
+                    got: 1
+                },
+                ..
+            })
+        ));
+        assert!(matches!(
+            run("\"str\".length;"),
+            Err(Error::Runtime {
+                err: RuntimeErr::OnlyInstancesHaveProperties { found: "a string" },
+                ..
+            })
+        ));
+        assert!(matches!(
+            run("var x = nil; x.y = 1;"),
+            Err(Error::Runtime {
+                err: RuntimeErr::OnlyInstancesHaveFields { found: "nil" },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn inheritance_and_super() {
+        let mut vm = Vm::new();
+        vm.interpret(
+            "class A {
+                init(x) { this.x = x; }
+                name() { return \"A\"; }
+                describe() { return this.name(); }
+            }
+            class B < A {
+                init(x, y) {
+                    super.init(x);
+                    this.y = y;
+                }
+                name() { return \"B\" + super.name(); }
+                getSuper() { return super.describe; }
+            }
+            class C < B {}
+            var c = C(1, 2);
+            var described = c.describe();
+            var total = c.x + c.y;
+            var viaBound = c.getSuper()();",
+        )
+        .unwrap();
+        let Value::Obj(described) = global(&mut vm, "described") else {
+            panic!("expected a string");
+        };
+        assert_eq!(vm.heap.as_str(described), Some("BA"));
+        assert_eq!(global(&mut vm, "total"), Value::Number(3.0));
+        let Value::Obj(via_bound) = global(&mut vm, "viaBound") else {
+            panic!("expected a string");
+        };
+        assert_eq!(vm.heap.as_str(via_bound), Some("BA"));
+        assert!(matches!(
+            run("var NotAClass = 1; class A < NotAClass {}"),
+            Err(Error::Runtime {
+                err: RuntimeErr::SuperclassMustBeClass { found: "a number" },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn natives_can_be_called_from_lox() {
+        let mut vm = Vm::new();
+        vm.define_native("greet", 1, |vm, args| {
+            let name = vm.as_str(args[0]).ok_or("greet needs a string")?;
+            let greeting = format!("Hello, {name}!");
+            Ok(vm.new_string(greeting))
+        });
+        vm.interpret("var a = greet(\"Lox\"); var t = clock();")
+            .unwrap();
+        let greeting = global(&mut vm, "a");
+        assert_eq!(vm.as_str(greeting), Some("Hello, Lox!"));
+        assert!(matches!(global(&mut vm, "t"), Value::Number(t) if t >= 0.0));
+        assert!(vm.stack.is_empty());
+
+        assert!(matches!(
+            vm.interpret("greet();"),
+            Err(Error::Runtime {
+                err: RuntimeErr::WrongArity {
+                    expected: 1,
+                    got: 0
+                },
+                ..
+            })
+        ));
+        assert!(matches!(
+            vm.interpret("greet(1);"),
+            Err(Error::Runtime { err: RuntimeErr::Native { name, .. }, .. }) if name == "greet"
+        ));
+        vm.define_native("fail", 0, |_, _| Err(RuntimeErr::StackOverflow.into()));
+        assert!(matches!(
+            vm.interpret("fail();"),
+            Err(Error::Runtime {
+                err: RuntimeErr::StackOverflow,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn natives_cannot_interpret_while_running() {
+        let mut vm = Vm::new();
+        vm.define_native("eval", 1, |vm, args| {
+            let source = vm.as_str(args[0]).ok_or("eval needs a string")?.to_owned();
+            vm.interpret(&source)?;
+            Ok(Value::Nil)
+        });
+        let result = vm.interpret("fun f() {\n  return eval(\"1;\");\n}\nf();");
+        let Err(Error::Runtime {
+            err: RuntimeErr::Native { name, source },
+            trace,
+            ..
+        }) = result
+        else {
+            panic!("expected a native error");
+        };
+        assert_eq!(name, "eval");
+        assert!(matches!(
+            source.downcast_ref(),
+            Some(Error::Runtime {
+                err: RuntimeErr::AlreadyRunning,
+                line: 2,
+                ..
+            })
+        ));
+        assert_eq!(trace.len(), 2);
+
+        // The failed call didn't disturb the VM.
+        vm.interpret("var ok = true;").unwrap();
+        assert_eq!(global(&mut vm, "ok"), Value::Bool(true));
+    }
+
+    #[test]
+    fn objects_allocated_by_natives_stay_alive() {
+        // With the gc-stress feature, the second allocation collects garbage.
+        let mut vm = Vm::new();
+        vm.define_native("twice", 0, |vm, _| {
+            let first = vm.new_string("first-1");
+            let _ = vm.new_string("second-2");
+            Ok(first)
+        });
+        vm.interpret("var s = twice();").unwrap();
+        let s = global(&mut vm, "s");
+        assert_eq!(vm.as_str(s), Some("first-1"));
+        assert!(vm.native_roots.is_empty());
+    }
+
+    #[test]
+    fn wide_constants_load_the_right_value() {
+        let mut vm = Vm::new();
+        let terms: Vec<String> = (0..300).map(|i| i.to_string()).collect();
+        vm.interpret(&format!("var sum = {};", terms.join(" + ")))
+            .unwrap();
+        assert_eq!(global(&mut vm, "sum"), Value::Number(44850.0));
+    }
+
+    #[test]
+    fn wide_names_refer_to_the_right_thing() {
+        let mut vm = Vm::new();
+        let globals: Vec<String> = (0..300).map(|i| format!("var k{i} = {i};")).collect();
+        let source = globals.concat()
+            + "class A { m() { return this.f; } }
+               class B < A { m() { return super.m() + k299; } }
+               fun f() { var b = B(); b.f = k150; return b.m(); }
+               var result = f();";
+        vm.interpret(&source).unwrap();
+        assert_eq!(global(&mut vm, "result"), Value::Number(449.0));
+    }
+
+    #[test]
+    fn strings_can_be_interpolated() {
+        let mut vm = Vm::new();
+        vm.interpret(
+            r#"
+            var name = "Lox";
+            var count = 2;
+            var a = "Hello ${name}, you have ${count + 1} items";
+            var b = "${count}${nil}${"in ${"ner"}"} \${x}";
+            "#,
+        )
+        .unwrap();
+        let a = global(&mut vm, "a");
+        assert_eq!(vm.as_str(a), Some("Hello Lox, you have 3 items"));
+        let b = global(&mut vm, "b");
+        assert_eq!(vm.as_str(b), Some("2nilin ner ${x}"));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn compile_errors_underline_the_source() {
+        let source = "var a = ;\n\tprint a + \"oops;\n";
+        let Err(Error::Compile(errors)) = run(source) else {
+            panic!("expected a compile error");
+        };
+        let rendered: Vec<_> = errors.iter().map(|err| err.render(source)).collect();
+        assert_eq!(
+            rendered,
+            [
+                "error: Expect expression.\n --> 1:9\n  |\n1 | var a = ;\n  |         ^",
+                "error: Unterminated string.\n --> 2:12\n  |\n2 | \tprint a + \"oops;\n  | \t          ^^^^^^",
+            ]
+        );
+    }
+
+    #[test]
+    fn runtime_errors_have_a_stack_trace() {
+        let mut vm = Vm::new();
+        let source = "fun outer() {\n  inner();\n}\nfun inner() { -nil; }\nouter();";
+        let Err(Error::Runtime { err, line, trace }) = vm.interpret(source) else {
+            panic!("expected a runtime error");
+        };
+        assert!(matches!(err, RuntimeErr::OperandMustBeNumber));
+        assert_eq!(line, 4);
+        let trace: Vec<_> = trace.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            trace,
+            [
+                "[line 4] in inner()",
+                "[line 2] in outer()",
+                "[line 5] in script"
+            ]
+        );
+
+        // The VM is reset, so it can keep running code afterwards.
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+        vm.interpret("var ok = outer != nil;").unwrap();
+        assert_eq!(global(&mut vm, "ok"), Value::Bool(true));
+
+        // Including closures that escaped from the failed call.
+        let source = "var get; { var x = 1; fun f() { return x; } get = f; nil(); }";
+        assert!(vm.interpret(source).is_err());
+        vm.interpret("var x = get();").unwrap();
+        assert_eq!(global(&mut vm, "x"), Value::Number(1.0));
+    }
 }